@@ -3,11 +3,23 @@
 #![deny(clippy::implicit_return)]
 #![allow(clippy::needless_return)]
 
-use serde_yaml::Value;
-use std::{fs, fmt};
-use crate::ykcmd;
+use std::{
+	fmt,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant, SystemTime},
+};
+use regex::bytes::Regex;
+use crate::{boottest, command, config, console, mock, qemu, sim, snmp, ykcmd};
+use crate::power::{self, PowerBackend};
 use log::debug;
 
+/* a board that prints any of these is not going to finish booting */
+pub const FAILURES: &[&str] = &[
+	"Kernel panic[^\r\n]*",
+	"Oops: [^\r\n]*",
+	"Unable to handle[^\r\n]*",
+];
+
 #[derive(Debug)]
 pub struct ConfigParsingError {
 	details: String
@@ -31,6 +43,52 @@ impl std::error::Error for ConfigParsingError {
 	}
 }
 
+#[derive(Debug)]
+pub struct BoardNotFoundError {
+	details: String
+}
+
+impl BoardNotFoundError {
+	pub fn new(board_name: &str) -> BoardNotFoundError {
+		return BoardNotFoundError{details: board_name.to_string()}
+	}
+}
+
+impl fmt::Display for BoardNotFoundError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Board not in config: {}", self.details)
+	}
+}
+
+impl std::error::Error for BoardNotFoundError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+#[derive(Debug)]
+pub struct ShutdownError {
+	details: String
+}
+
+impl ShutdownError {
+	pub fn new(msg: &str) -> ShutdownError {
+		return ShutdownError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for ShutdownError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Shutdown failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for ShutdownError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
 #[derive(Clone)]
 #[derive(Debug)]
 pub struct Board {
@@ -38,8 +96,15 @@ pub struct Board {
 	pub yk_serial_number: String,
 	pub yk_port_number: String,
 	pub power_source: String,
-	pub powered: bool,
+	pub power: Arc<dyn PowerBackend>,
 	pub primary_uart: String,
+	pub boot_steps: Vec<config::BootStep>,
+	pub credentials: config::Credentials,
+	pub transcripts: config::TranscriptConfig,
+	pub baselines: config::BaselineConfig,
+	pub shutdown_timeout: Duration,
+	/// when power last went on, shared between clones of the board
+	pub powered_on: Arc<Mutex<Option<SystemTime>>>,
 }
 
 impl Default for Board {
@@ -50,12 +115,60 @@ impl Default for Board {
 			yk_serial_number: "n/a".to_string(),
 			yk_port_number: "n/a".to_string(),
 			power_source: "n/a".to_string(),
-			powered: false,
+			power: Arc::new(power::Unsupported::new("n/a")),
 			primary_uart: "n/a".to_string(),
+			boot_steps: Vec::new(),
+			credentials: config::Credentials::default(),
+			transcripts: config::TranscriptConfig::default(),
+			baselines: config::BaselineConfig::default(),
+			shutdown_timeout: Duration::from_secs(60),
+			powered_on: Arc::new(Mutex::new(None)),
 		}
 	}
 }
 
+/// What to wait for on a board's uart, all at once. Retry patterns mean the
+/// board is still getting there, so the wait starts over when one matches.
+#[derive(Clone, Debug, Default)]
+pub struct Expect {
+	pub success: Vec<String>,
+	pub failure: Vec<String>,
+	pub retry: Vec<String>,
+	pub timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+	Success,
+	Failure,
+	Timeout,
+}
+
+/// How an expect ended: the pattern that matched, if any, what it matched
+/// and everything seen while waiting.
+#[derive(Clone, Debug)]
+pub struct Matched {
+	pub outcome: Outcome,
+	pub pattern: String,
+	pub text: String,
+	pub transcript: String,
+}
+
+/// how long after power went on a boot step matched
+#[derive(Clone, Debug)]
+pub struct Stage {
+	pub name: String,
+	pub after: Duration,
+}
+
+impl Board {
+	/// when this lab last turned the board on, if it is still meant to be
+	pub fn powered_on_at(&self) -> Option<SystemTime>
+	{
+		return *self.powered_on.lock().unwrap()
+	}
+}
+
 pub trait Status {
 	fn is_powered(&self) -> Result<bool, Box<dyn std::error::Error>>;
 }
@@ -71,88 +184,201 @@ pub trait Ops {
 	fn power_off(&self) -> Result<(), Box<dyn std::error::Error>>;
 	fn power_on(&self) -> Result<(), Box<dyn std::error::Error>>;
 	fn reboot(&self) -> Result<(), Box<dyn std::error::Error>>;
-	fn expect_boot(&self, console_log: &mut Vec<String>) -> Result<(), Box<dyn std::error::Error>>;
-	fn expect_shutdown(&self, console_log: &mut Vec<String>) -> Result<(), Box<dyn std::error::Error>>;
+	fn expect(&self, session: &mut console::Session, expect: &Expect)
+	-> Result<Matched, Box<dyn std::error::Error>>;
+	fn expect_boot(&self, session: &mut console::Session, console_log: &mut Vec<String>,
+		       stages: &mut Vec<Stage>)
+	-> Result<(), Box<dyn std::error::Error>>;
+	fn expect_shutdown(&self, session: &mut console::Session, console_log: &mut Vec<String>)
+	-> Result<(), Box<dyn std::error::Error>>;
 }
 
 impl Ops for Board {
 	fn power_off(&self) -> Result<(), Box<dyn std::error::Error>>
 	{
-		return ykcmd::power_off(self.name.clone(),
-					self.yk_serial_number.clone(),
-					self.yk_port_number.clone(),
-					self.power_source.clone());
+		ykcmd::power_off(self)?;
+		*self.powered_on.lock().unwrap() = None;
+
+		return Ok(())
 	}
 
 	fn power_on(&self) -> Result<(), Box<dyn std::error::Error>>
 	{
-		return ykcmd::power_on(self.name.clone(),
-				       self.yk_serial_number.clone(),
-				       self.yk_port_number.clone(),
-				       self.power_source.clone());
+		ykcmd::power_on(self)?;
+		*self.powered_on.lock().unwrap() = Some(SystemTime::now());
+
+		return Ok(())
 	}
 
 	fn reboot(&self) -> Result<(), Box<dyn std::error::Error>>
 	{
-		return ykcmd::reboot(self.name.clone(),
-				     self.yk_serial_number.clone(),
-				     self.yk_port_number.clone(),
-				     self.power_source.clone());
+		*self.powered_on.lock().unwrap() = None;
+		ykcmd::reboot(self)?;
+		*self.powered_on.lock().unwrap() = Some(SystemTime::now());
+
+		return Ok(())
 	}
 
-	fn expect_boot(&self, console_log: &mut Vec<String>) -> Result<(), Box<dyn std::error::Error>>
+	fn expect(&self, session: &mut console::Session, expect: &Expect)
+	-> Result<Matched, Box<dyn std::error::Error>>
 	{
-		let uart = &self.primary_uart;
-		let port = serialport::new(uart, 115_200).open()?;
-		let read_port = port.try_clone()?;
-		let write_port = port.try_clone()?;
+		let patterns: Vec<(Option<Outcome>, &String)> = expect.success
+			.iter()
+			.map(|pattern| return (Some(Outcome::Success), pattern))
+			.chain(expect.failure.iter().map(|pattern| return (Some(Outcome::Failure), pattern)))
+			.chain(expect.retry.iter().map(|pattern| return (None, pattern)))
+			.collect();
+		let regexes = patterns
+			.iter()
+			.map(|(_, pattern)| return Regex::new(pattern))
+			.collect::<Result<Vec<Regex>, regex::Error>>()?;
+		let mut transcript = String::new();
+		let mut deadline = Instant::now() + expect.timeout;
+
+		/* nothing is going to turn up, so make out the first thing did */
+		if power::dry_run() {
+			let (_, pattern) = patterns[0];
+			println!("{}: would wait up to {}s for {}", self.name, expect.timeout.as_secs(),
+				 pattern);
+			return Ok(Matched {
+				outcome: Outcome::Success,
+				pattern: pattern.clone(),
+				text: String::new(),
+				transcript,
+			})
+		}
 
-		let mut stream = rexpect::session::spawn_stream(read_port, write_port, Some(120000));
+		loop {
+			let (which, before, text) = match session.find(&regexes, deadline)? {
+				Some(found) => found,
+				None => {
+					transcript.push_str(&session.drain());
+					return Ok(Matched {
+						outcome: Outcome::Timeout,
+						pattern: String::new(),
+						text: String::new(),
+						transcript,
+					})
+				},
+			};
+
+			transcript.push_str(&before);
+			transcript.push_str(&text);
+
+			let (outcome, pattern) = patterns[which];
+			match outcome {
+				Some(outcome) => {
+					return Ok(Matched {
+						outcome,
+						pattern: pattern.clone(),
+						text,
+						transcript,
+					})
+				},
+				None => {
+					debug!("{}: saw {}, waiting some more", self.name, text.trim());
+					session.mark(&format!("Still going: {}", text.trim()));
+					deadline = Instant::now() + expect.timeout;
+				},
+			}
+		}
+	}
 
+	fn expect_boot(&self, session: &mut console::Session, console_log: &mut Vec<String>,
+		       stages: &mut Vec<Stage>)
+	-> Result<(), Box<dyn std::error::Error>>
+	{
 		debug!("expecting on uart with path {}", self.primary_uart.clone());
 
-		let (output, _) = stream.exp_regex(".*U-Boot.*")?;
-		console_log.push(output);
-		debug!("Found U-Boot!");
-
-		let (output, _) = stream.exp_regex(".*Linux version.*")?;
-		console_log.push(output);
-		debug!("Found Linux!");
-
-		let (output, _) = stream.exp_regex(".*init.*")?;
-		console_log.push(output);
-		debug!("Found init!");
-
-		let (output, _) = stream.exp_regex(".*login: .*")?;
-		console_log.push(output);
-		stream.send_line("root")?;
-
-		let (output, _) = stream.exp_regex(".*assword: ")?;
-		console_log.push(output);
-		debug!("Waiting for password!");
-
-		stream.send_line("fedora_rocks!")?;
-		let (output, _) = stream.exp_regex(".*#.*")?;
-		console_log.push(output);
-		debug!("Logged in!");
+		for step in self.boot_steps.iter() {
+			let label = step.label();
+
+			if let Some(success) = &step.expect {
+				let expect = Expect {
+					success: vec![success.clone()],
+					failure: step.fail
+						.iter()
+						.cloned()
+						.chain(FAILURES.iter().map(|failure| return failure.to_string()))
+						.collect(),
+					retry: step.retry.iter().cloned().collect(),
+					timeout: Duration::from_secs(step.timeout),
+				};
+				let matched = self.expect(session, &expect)?;
+				console_log.push(matched.transcript);
+
+				debug!("{}: {:?} on {}", label, matched.outcome, matched.pattern);
+
+				match matched.outcome {
+					Outcome::Success => (),
+					Outcome::Failure => {
+						session.mark(&format!("{} failed on: {}", label, matched.text.trim()));
+						return Err(Box::new(boottest::BootTestError::new(&format!(
+							"{}: saw \"{}\"", label, matched.text.trim()))))
+					},
+					Outcome::Timeout => {
+						session.mark(&format!("{} timed out", label));
+						return Err(Box::new(boottest::BootTestError::new(&format!(
+							"{}: nothing after {}s", label, step.timeout))))
+					},
+				}
+
+				match self.powered_on_at() {
+					Some(on) => {
+						let after = on.elapsed().unwrap_or_default();
+						session.mark(&format!("Found {}! after {:.1}s", label,
+								      after.as_secs_f64()));
+						stages.push(Stage {
+							name: label.clone(),
+							after,
+						});
+					},
+					None => session.mark(&format!("Found {}!", label)),
+				}
+				debug!("Found {}!", label);
+			}
+
+			if let Some(send) = &step.send {
+				let mut line = send.replace("{user}", &self.credentials.user);
+
+				if line.contains("{password}") {
+					let password = self.credentials.password()?;
+					session.redact(password.expose());
+					line = line.replace("{password}", password.expose());
+				}
+
+				session.send_line(&line)?;
+			}
+		}
 
 		return Ok(())
 	}
 
-	fn expect_shutdown(&self, console_log: &mut Vec<String>) -> Result<(), Box<dyn std::error::Error>>
+	fn expect_shutdown(&self, session: &mut console::Session, console_log: &mut Vec<String>)
+	-> Result<(), Box<dyn std::error::Error>>
 	{
-		let uart = &self.primary_uart;
-		let port = serialport::new(uart, 115_200).open()?;
-		let read_port = port.try_clone()?;
-		let write_port = port.try_clone()?;
-
-		let mut stream = rexpect::session::spawn_stream(read_port, write_port, Some(120000));
-
 		debug!("expecting on uart with path {}", self.primary_uart.clone());
-		stream.send_line("poweroff")?;
+		session.send_line("poweroff")?;
+		session.mark("Powering off!");
 		debug!("Powering off!");
-		let (output, _) = stream.exp_regex(".*reboot: System halted.*")?;
-		console_log.push(output);
+
+		let expect = Expect {
+			success: vec![".*reboot: System halted.*".to_string()],
+			failure: FAILURES.iter().map(|failure| return failure.to_string()).collect(),
+			retry: Vec::new(),
+			timeout: session.timeout,
+		};
+		let matched = self.expect(session, &expect)?;
+		console_log.push(matched.transcript);
+
+		match matched.outcome {
+			Outcome::Success => (),
+			Outcome::Failure => return Err(Box::new(ShutdownError::new(&format!(
+				"saw \"{}\"", matched.text.trim())))),
+			Outcome::Timeout => return Err(Box::new(ShutdownError::new(&format!(
+				"nothing after {}s", session.timeout.as_secs())))),
+		}
+		session.mark("Shut down!");
 		debug!("Shut down!");
 
 		return Ok(())
@@ -160,120 +386,142 @@ impl Ops for Board {
 
 }
 
-fn populate_board(board: &mut Board, board_config: Value)
+fn populate_board(board: &mut Board, board_config: &config::BoardConfig, config: &config::Config)
 -> Result<(),Box<dyn std::error::Error>>
 {
-	board.yk_serial_number = board_config
-		.get("serial")
-		.ok_or_else(|| return ConfigParsingError::new("No serial number found"))?
-		.as_str()
-		.ok_or_else(|| return ConfigParsingError::new("Serial number was not a string"))?
-		.to_owned();
-
-	board.yk_port_number = board_config
-		.get("port")
-		.ok_or_else(|| return ConfigParsingError::new("No port number found"))?
-		.as_str()
-		.ok_or_else(|| return ConfigParsingError::new("Port number was not a string"))?
-		.to_owned();
-
-	board.power_source = board_config
-		.get("type")
-		.ok_or_else(|| return ConfigParsingError::new("No type found"))?
-		.as_str()
-		.ok_or_else(|| return ConfigParsingError::new("Type was not a string"))?
-		.to_owned();
-
-	let _who_cares = populate_uart(board, board_config);
+	board.yk_serial_number = board_config.serial.clone();
+	board.yk_port_number = board_config.port.clone();
+	board.power_source = board_config.power_source.clone();
+
+	/* the PDU & outlet stand in for a hub & port, when it comes to polling */
+	if let Some(pdu) = &board_config.snmp {
+		board.yk_serial_number = pdu.host.clone();
+		board.yk_port_number = pdu.outlet.to_string();
+	}
+
+	board.power = match (config.qemu(board_config), config.sim(board_config), &board_config.command) {
+		(Some(qemu), _, _) => {
+			let vm = qemu::Qemu::new(&board.name, &qemu);
+			board.primary_uart = format!("{}{}", console::SOCKET, vm.socket().display());
+			Arc::new(vm)
+		},
+		(None, Some(sim), _) => {
+			let switch = sim::Switch::new(&board.name, &sim);
+			board.primary_uart = switch.tty().display().to_string();
+			Arc::new(switch)
+		},
+		(None, None, Some(command)) => {
+			Arc::new(command::Scripted::new(&board.name, &board.yk_serial_number,
+							&board.yk_port_number, command))
+		},
+		(None, None, None) if board_config.snmp.is_some() => {
+			Arc::new(snmp::Snmp::new(&config.snmp(board_config).unwrap()))
+		},
+		(None, None, None) if board.power_source == "mock" => {
+			Arc::new(mock::Mock::new(&board.yk_serial_number, &board.yk_port_number,
+						 &config.mock().file))
+		},
+		(None, None, None) => power::backend_for(&board.power_source,
+					   &board_config.driver,
+					   &board.yk_serial_number,
+					   &board.yk_port_number),
+	};
+	if power::dry_run() {
+		board.power = Arc::new(power::DryRun::new(&board.name, board.power.clone()));
+	}
+
+	populate_uart(board, board_config);
+	board.boot_steps = config.boot_steps(board_config)
+		.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", board.name, e)))?;
+	board.credentials = config.credentials(board_config)
+		.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", board.name, e)))?;
+	board.transcripts = config.transcripts();
+	board.baselines = config.baselines();
+	board.shutdown_timeout = Duration::from_secs(board_config.shutdown_timeout);
 
 	return Ok(());
 }
 
-fn populate_uart(board: &mut Board, board_config: Value)
--> Result<(),Box<dyn std::error::Error>>
+fn populate_uart(board: &mut Board, board_config: &config::BoardConfig)
 {
-	board.yk_serial_number = board_config
-		.get("serial")
-		.ok_or_else(|| return ConfigParsingError::new("No serial number found"))?
-		.as_str()
-		.ok_or_else(|| return ConfigParsingError::new("Serial number was not a string"))?
-		.to_owned();
-	
-	let uart_config = board_config
-		.get("uart")
-		.ok_or_else(|| return ConfigParsingError::new("No uart config found"))?;
-
-	let uart_by_id = uart_config
-		.get("pattern")
-		.ok_or_else(|| return ConfigParsingError::new("No uart pattern found"))?
-		.as_str()
-		.ok_or_else(|| return ConfigParsingError::new("uart pattern was not a string"))?
-		.to_owned();
-
-	let uart_primary = uart_config
-		.get("primary")
-		.ok_or_else(|| return ConfigParsingError::new("No uart primary found"))?
-		.as_str()
-		.ok_or_else(|| return ConfigParsingError::new("uart primary was not a string"))?
-		.to_owned();
-	
-	board.primary_uart = format!("/dev/serial/by-id/{}-{}", uart_by_id, uart_primary);
-	debug!("uart found with path {}", board.primary_uart.clone());
-
-	return Ok(());
+	if let Some(uart) = &board_config.uart {
+		board.primary_uart = uart.path(&uart.primary);
+		debug!("uart found with path {}", board.primary_uart.clone());
+	}
 }
 
-pub fn get_all_boards_from_config(input_file: String)
--> Result<Vec<Board>,Box<dyn std::error::Error>>
+/// every board in an already loaded config
+pub fn boards_from(config: &config::Config) -> Result<Vec<Board>, Box<dyn std::error::Error>>
 {
 	let mut boards: Vec<Board> = Vec::new();
-	let contents = fs::read_to_string(input_file)?;
 
-	let config: Value = serde_yaml::from_str(&contents)?;
-
-	let board_configs = config
-		.get("boards")
-		.ok_or_else(|| return ConfigParsingError::new("No boards found"))?
-		.as_mapping();
-	
-	let board_configs_iter = board_configs
-		.unwrap()
-		.iter();
-
-	for board_config in board_configs_iter {
+	for (name, board_config) in config.boards.iter() {
 		let mut board = Board {
-			name: board_config.0
-				.as_str()
-				.ok_or_else(|| return ConfigParsingError::new("name was not a string"))?
-				.to_string(),
+			name: name.clone(),
 			..Default::default()
 		};
-		populate_board(&mut board, board_config.1.to_owned())?;
+		populate_board(&mut board, board_config, config)?;
 		boards.push(board);
 	}
 
-	return Ok(boards.clone());
+	return Ok(boards);
 }
 
-pub fn get_board_from_config(board_name: String, input_file: String)
--> Result<Board, Box<dyn std::error::Error>>
+/// Look up boards by name, keeping the order they were asked for in. No
+/// names at all means every board in the config.
+pub fn boards_named(board_names: &[String], config: &config::Config)
+-> Result<Vec<Board>, Box<dyn std::error::Error>>
 {
-	let contents = fs::read_to_string(input_file)?;
+	let all_boards = boards_from(config)?;
 
-	let config: Value = serde_yaml::from_str(&contents)?;
+	if board_names.is_empty() {
+		return Ok(all_boards)
+	}
 
-	let board_config = config
-		.get("boards")
-		.ok_or_else(|| return ConfigParsingError::new("No boards found"))?
-		.get(board_name.clone())
-		.ok_or_else(|| return ConfigParsingError::new("Requested board not found"))?;
+	let mut boards: Vec<Board> = Vec::new();
+	for board_name in board_names.iter() {
+		let board = all_boards
+			.iter()
+			.find(|board| return board.name == *board_name)
+			.ok_or_else(|| return BoardNotFoundError::new(board_name))?;
+		boards.push(board.clone());
+	}
 
-	let mut board = Board {
-		name: board_name,
-		..Default::default()
-	};
-	populate_board(&mut board, board_config.to_owned())?;
+	return Ok(boards);
+}
 
-	return Ok(board.clone());
+/// boards_named(), loading the config first
+pub fn get_boards_from_config(board_names: &[String], input_file: String)
+-> Result<Vec<Board>, Box<dyn std::error::Error>>
+{
+	let config = config::load(&input_file)?;
+	return boards_named(board_names, &config)
 }
 
+/// Every board in any of the groups, each only the once, in the order the
+/// groups list them in
+pub fn boards_in_groups(groups: &[String], config: &config::Config)
+-> Result<Vec<Board>, Box<dyn std::error::Error>>
+{
+	let all_boards = boards_from(config)?;
+	let mut boards: Vec<Board> = Vec::new();
+
+	for group in groups.iter() {
+		let members = config.groups
+			.get(group)
+			.ok_or_else(|| return BoardNotFoundError::new(&format!("group {}", group)))?;
+
+		for member in members.iter() {
+			if boards.iter().any(|board| return board.name == *member) {
+				continue;
+			}
+
+			/* load() made sure every member is a board */
+			if let Some(board) = all_boards.iter().find(|board| return board.name == *member) {
+				boards.push(board.clone());
+			}
+		}
+	}
+
+	return Ok(boards);
+}
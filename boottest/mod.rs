@@ -0,0 +1,690 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use serde::Serialize;
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt, fs,
+	path::{Path, PathBuf},
+	sync::{Condvar, Mutex},
+	thread,
+	time::{Duration, Instant},
+};
+use log::error;
+
+use crate::boards::{self, Board, Ops};
+use crate::{config, console, power, results};
+use crate::config::TranscriptConfig;
+use crate::jobs::Cancel;
+use crate::transcript::{self, Transcript};
+
+/* expect_boot sets its own per step, this covers the shutdown */
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+pub struct BootTestError {
+	details: String
+}
+
+impl BootTestError {
+	pub fn new(msg: &str) -> BootTestError {
+		return BootTestError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for BootTestError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Boot test failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for BootTestError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+/// per board, how many seconds after power on each stage should show up by
+type Baselines = BTreeMap<String, BTreeMap<String, f64>>;
+
+fn load_baselines(path: &Path) -> Result<Baselines, Box<dyn std::error::Error>>
+{
+	if !path.exists() {
+		return Ok(Baselines::new())
+	}
+
+	let text = fs::read_to_string(path)?;
+	let baselines = serde_json::from_str(&text)
+		.map_err(|e| return BootTestError::new(&format!("{}: {}", path.display(), e)))?;
+
+	return Ok(baselines)
+}
+
+fn save_baselines(path: &Path, baselines: &Baselines) -> Result<(), Box<dyn std::error::Error>>
+{
+	let text = serde_json::to_string_pretty(baselines)?;
+	fs::write(path, text + "\n")
+		.map_err(|e| return BootTestError::new(&format!("{}: {}", path.display(), e)))?;
+
+	return Ok(())
+}
+
+/// A boot stage, how long after power on it took to show up and how that
+/// compares to the baseline, if there is one
+#[derive(Clone, Debug, Serialize)]
+pub struct StageTime {
+	pub name: String,
+	pub seconds: f64,
+	pub baseline: Option<f64>,
+	pub slow: bool,
+}
+
+/// How a board's boot test went, and where to find everything it printed
+pub struct Report {
+	pub board: String,
+	/// which run this was, when boards are tested over & over
+	pub run: Option<usize>,
+	/// what went wrong, as a string so reports can be passed between threads
+	pub result: Result<(), String>,
+	pub transcript: Option<PathBuf>,
+	pub stages: Vec<StageTime>,
+	/// start to finish, shutdown & all
+	pub duration: Duration,
+}
+
+/// the bits of a report that go into json
+#[derive(Serialize)]
+struct Summary<'a> {
+	board: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	run: Option<usize>,
+	passed: bool,
+	error: Option<String>,
+	transcript: Option<&'a Path>,
+	stages: &'a [StageTime],
+}
+
+impl Report {
+	fn summary(&self) -> Summary<'_>
+	{
+		return Summary {
+			board: &self.board,
+			run: self.run,
+			passed: self.result.is_ok(),
+			error: self.result.clone().err(),
+			transcript: self.transcript.as_deref(),
+			stages: &self.stages,
+		}
+	}
+}
+
+/// how long each stage took next to how long it is meant to take
+fn stage_times(board: &Board, stages: &[boards::Stage], baseline: Option<&BTreeMap<String, f64>>)
+-> Vec<StageTime>
+{
+	return stages
+		.iter()
+		.map(|stage| {
+			let seconds = stage.after.as_secs_f64();
+			let expected = baseline.and_then(|baseline| return baseline.get(&stage.name).copied());
+			let allowed = expected.map(|expected| {
+				return expected * (1.0 + board.baselines.tolerance / 100.0) + board.baselines.slack
+			});
+
+			return StageTime {
+				name: stage.name.clone(),
+				seconds,
+				baseline: expected,
+				slow: allowed.is_some_and(|allowed| return seconds > allowed),
+			}
+		})
+		.collect()
+}
+
+/// power off a board whose test has already gone wrong, where why it went
+/// wrong matters more than the power not going off as well
+fn cut_power(board: &Board)
+{
+	if let Err(e) = board.power_off() {
+		error!("{}: could not power off: {}", board.name, e);
+	}
+}
+
+fn boot(board: &Board, session: &mut console::Session, cancel: &Cancel, stages: &mut Vec<boards::Stage>)
+-> Result<(), Box<dyn std::error::Error>>
+{
+	let mut output = Vec::new();
+
+	session.watch(cancel);
+	cancel.check()?;
+	board.reboot()?;
+	session.mark("Boot test: power cycled");
+	let ret = cancel.check().and_then(|_| return board.expect_boot(session, &mut output, stages));
+
+	if ret.is_err() {
+		error!("Expect boot failed!");
+		session.mark("Boot test: failed");
+		cut_power(board);
+		return ret;
+	}
+
+	if let Err(e) = cancel.check() {
+		cut_power(board);
+		return Err(e);
+	}
+
+	session.timeout = SHUTDOWN_TIMEOUT;
+	let shutdown = board.expect_shutdown(session, &mut output);
+	/* cancelled half way through shutting down is still to end up off */
+	if cancel.is_cancelled() {
+		cut_power(board);
+	}
+	shutdown?;
+	session.mark("Boot test: passed");
+	return board.power_off()
+}
+
+impl Report {
+	fn new(board: &Board) -> Report
+	{
+		return Report {
+			board: board.name.clone(),
+			run: None,
+			result: Ok(()),
+			transcript: None,
+			stages: Vec::new(),
+			duration: Duration::ZERO,
+		}
+	}
+
+	/// the board, and which run it was if there's more than the one
+	pub fn name(&self) -> String
+	{
+		match self.run {
+			Some(run) => return format!("{} run {}", self.board, run),
+			None => return self.board.clone(),
+		}
+	}
+}
+
+/// Power cycle a board, walk through its boot steps, then shut it down
+/// cleanly & cut the power again, keeping a transcript of the lot and
+/// timing each stage against the baseline.
+pub fn test(board: &Board, cancel: &Cancel) -> Report
+{
+	let started = Instant::now();
+	let mut report = Report::new(board);
+
+	report.result = attempt(board, cancel, &mut report).map_err(|e| return e.to_string());
+	report.duration = started.elapsed();
+
+	return report
+}
+
+fn attempt(board: &Board, cancel: &Cancel, report: &mut Report)
+-> Result<(), Box<dyn std::error::Error>>
+{
+	/* attach before power goes on so not a byte of the boot is missed */
+	let console = console::attach(&board.primary_uart)?;
+	/* a dry run has nothing to record, and would push real runs out */
+	let transcript = match power::dry_run() {
+		true => None,
+		false => Some(Transcript::start(board, &console)?),
+	};
+	let mut session = console.session(SHUTDOWN_TIMEOUT);
+	let mut stages = Vec::new();
+
+	let result = boot(board, &mut session, cancel, &mut stages);
+
+	if let Some(transcript) = transcript {
+		let path = transcript.finish();
+		session.mark(&format!("Boot test: transcript in {}", path.display()));
+		report.transcript = Some(path);
+	}
+
+	let baselines = load_baselines(&board.baselines.file);
+	let baseline = baselines.as_ref().ok().and_then(|baselines| return baselines.get(&board.name));
+	report.stages = stage_times(board, &stages, baseline);
+
+	/* whatever broke first is the more interesting failure */
+	result?;
+	baselines?;
+
+	let slow: Vec<String> = report.stages
+		.iter()
+		.filter(|stage| return stage.slow)
+		.map(|stage| {
+			return format!("{} took {:.1}s, {:.1}s in the baseline", stage.name, stage.seconds,
+				       stage.baseline.unwrap_or_default())
+		})
+		.collect();
+
+	if !slow.is_empty() {
+		session.mark(&format!("Boot test: too slow, {}", slow.join(", ")));
+		return Err(Box::new(BootTestError::new(&slow.join(", "))))
+	}
+
+	return Ok(())
+}
+
+/// test(), for when only whether it passed matters
+pub fn run(board: &Board, cancel: &Cancel) -> Result<(), Box<dyn std::error::Error>>
+{
+	return attempt(board, cancel, &mut Report::new(board))
+}
+
+fn print_report(report: &Report)
+{
+	let name = report.name();
+
+	match &report.result {
+		Ok(()) => println!("{}: passed", name),
+		Err(e) => println!("{}: {}", name, e),
+	}
+
+	if !report.stages.is_empty() {
+		let stages: Vec<String> = report.stages
+			.iter()
+			.map(|stage| {
+				match stage.baseline {
+					Some(baseline) => return format!("{} {:.1}s ({:.1}s)", stage.name,
+									 stage.seconds, baseline),
+					None => return format!("{} {:.1}s", stage.name, stage.seconds),
+				}
+			})
+			.collect();
+		println!("{}: {}", name, stages.join(", "));
+	}
+
+	if let Some(transcript) = &report.transcript {
+		println!("{}: transcript in {}", name, transcript.display());
+	}
+}
+
+/// the stage times of boards that passed become what they get held to
+fn save_baseline(boards: &[Board], reports: &[Report]) -> Result<(), Box<dyn std::error::Error>>
+{
+	for (board, report) in boards.iter().zip(reports.iter()) {
+		if report.result.is_err() {
+			continue;
+		}
+
+		let mut baselines = load_baselines(&board.baselines.file)?;
+		baselines.insert(board.name.clone(), report.stages
+			.iter()
+			.map(|stage| return (stage.name.clone(), stage.seconds))
+			.collect());
+		save_baselines(&board.baselines.file, &baselines)?;
+	}
+
+	return Ok(())
+}
+
+/// which boards boot-test picks, & how it reports back on top of a line per
+/// board
+#[derive(clap::Args, Debug)]
+pub struct Options {
+	/// test every board in the config
+	#[clap(long, conflicts_with = "boards")]
+	pub all: bool,
+
+	/// test the boards in a group from the config, as well as any named
+	#[clap(long, value_name = "GROUP", multiple_occurrences = true)]
+	pub group: Vec<String>,
+
+	/// print json rather than a line per board
+	#[clap(long)]
+	pub json: bool,
+
+	/// keep the stage times of boards that pass as their new baseline
+	#[clap(long)]
+	pub save_baseline: bool,
+
+	/// write JUnit XML results to this file
+	#[clap(long, value_name = "FILE")]
+	pub junit: Option<PathBuf>,
+
+	/// write TAP results to this file
+	#[clap(long, value_name = "FILE")]
+	pub tap: Option<PathBuf>,
+
+	/// test each board this many times over
+	#[clap(long, value_name = "N",
+	       value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+	pub iterations: Option<usize>,
+
+	/// keep testing each board until it fails, or has been tested
+	/// --iterations times
+	#[clap(long)]
+	pub until_fail: bool,
+}
+
+impl Options {
+	/// whether this is a soak test, with boards tested more than the once
+	fn soak(&self) -> bool
+	{
+		return self.until_fail || self.iterations.unwrap_or(1) > 1
+	}
+}
+
+/// How many boards on each hub are in the middle of a boot test, so as not
+/// to power too many up at once off the one supply
+struct Slots {
+	limit: usize,
+	busy: Mutex<HashMap<String, usize>>,
+	freed: Condvar,
+}
+
+/// a board's turn on its hub, given back when dropped
+struct Slot<'a> {
+	slots: &'a Slots,
+	hub: String,
+}
+
+impl Slots {
+	fn new(limit: usize) -> Slots
+	{
+		return Slots {
+			limit,
+			busy: Mutex::new(HashMap::new()),
+			freed: Condvar::new(),
+		}
+	}
+
+	fn take(&self, hub: &str) -> Slot<'_>
+	{
+		let mut busy = self.busy.lock().unwrap();
+
+		while self.limit > 0 && busy.get(hub).copied().unwrap_or(0) >= self.limit {
+			busy = self.freed.wait(busy).unwrap();
+		}
+		*busy.entry(hub.to_string()).or_insert(0) += 1;
+
+		return Slot {
+			slots: self,
+			hub: hub.to_string(),
+		}
+	}
+}
+
+impl Drop for Slot<'_> {
+	fn drop(&mut self)
+	{
+		let mut busy = self.slots.busy.lock().unwrap();
+
+		if let Some(count) = busy.get_mut(&self.hub) {
+			*count -= 1;
+		}
+		self.slots.freed.notify_all();
+	}
+}
+
+fn select(board_names: &[String], config: &config::Config, options: &Options)
+-> Result<Vec<Board>, Box<dyn std::error::Error>>
+{
+	if options.all {
+		return boards::boards_from(config)
+	}
+
+	/* no names would mean every board, which only --all should */
+	let mut selected = match board_names.is_empty() {
+		true => Vec::new(),
+		false => boards::boards_named(board_names, config)?,
+	};
+
+	if !options.group.is_empty() {
+		for board in boards::boards_in_groups(&options.group, config)? {
+			if !selected.iter().any(|other: &Board| return other.name == board.name) {
+				selected.push(board);
+			}
+		}
+	}
+
+	return Ok(selected)
+}
+
+/// Boot test a board as many times as asked, giving up on its turn on the
+/// hub between runs so the other boards on it don't have to wait for all
+/// of them.
+fn soak(board: &Board, options: &Options, slots: &Slots, printing: &Mutex<()>) -> Vec<Report>
+{
+	let iterations = match (options.iterations, options.until_fail) {
+		(Some(iterations), _) => iterations,
+		(None, true) => usize::MAX,
+		(None, false) => 1,
+	};
+	let mut reports = Vec::new();
+
+	/*
+	 * the soak report, JUnit & TAP point at every run's transcript, so none
+	 * of them go until the soak is over
+	 */
+	let soaking = match options.soak() {
+		true => Board {
+			transcripts: TranscriptConfig { keep: 0, ..board.transcripts.clone() },
+			..board.clone()
+		},
+		false => board.clone(),
+	};
+
+	for run in 1..=iterations {
+		let slot = slots.take(&board.power.hub());
+		let mut report = test(&soaking, &Cancel::default());
+		drop(slot);
+
+		if options.soak() {
+			report.run = Some(run);
+		}
+
+		if !options.json {
+			let _printing = printing.lock().unwrap();
+			print_report(&report);
+		}
+
+		let failed = report.result.is_err();
+		reports.push(report);
+
+		if failed && options.until_fail {
+			break;
+		}
+	}
+
+	if options.soak() && !power::dry_run() && board.transcripts.keep > 0 {
+		transcript::tidy(board, board.transcripts.keep.max(reports.len()));
+	}
+
+	return reports
+}
+
+/// Boot test boards all at once, each on a thread of its own but only so
+/// many at a time per hub, reporting on each as it finishes and with a
+/// table of every stage at the end, or for a soak test how reliable each
+/// board was. With json, everything comes out all at once at the end.
+pub fn run_boards(board_names: &[String], input_file: String, options: &Options)
+-> Result<(), Box<dyn std::error::Error>>
+{
+	let config = config::load(&input_file)?;
+	let boards = select(board_names, &config, options)?;
+	let slots = Slots::new(config.max_per_hub);
+	let printing = Mutex::new(());
+
+	let per_board: Vec<Vec<Report>> = thread::scope(|scope| {
+		let running: Vec<thread::ScopedJoinHandle<Vec<Report>>> = boards
+			.iter()
+			.map(|board| {
+				let slots = &slots;
+				let printing = &printing;
+
+				return scope.spawn(move || {
+					return soak(board, options, slots, printing)
+				})
+			})
+			.collect();
+
+		return running
+			.into_iter()
+			.map(|thread| return thread.join().unwrap())
+			.collect()
+	});
+
+	if !options.json && options.soak() {
+		println!();
+		results::print_soak(&boards, &per_board);
+	}
+
+	/* from here on, a board for every run */
+	let boards: Vec<Board> = boards
+		.iter()
+		.zip(per_board.iter())
+		.flat_map(|(board, reports)| return vec![board.clone(); reports.len()])
+		.collect();
+	let reports: Vec<Report> = per_board.into_iter().flatten().collect();
+
+	if !options.json && !options.soak() {
+		println!();
+		results::print_matrix(&boards, &reports);
+	}
+
+	if options.json {
+		let summaries: Vec<Summary> = reports.iter().map(|report| return report.summary()).collect();
+		println!("{}", serde_json::to_string_pretty(&summaries)?);
+	}
+
+	if let Some(path) = &options.junit {
+		results::write_junit(path, &boards, &reports)?;
+	}
+
+	if let Some(path) = &options.tap {
+		results::write_tap(path, &boards, &reports)?;
+	}
+
+	if options.save_baseline && power::dry_run() {
+		println!("not saving baselines from a dry run");
+	} else if options.save_baseline {
+		save_baseline(&boards, &reports)?;
+	}
+
+	let failed = reports.iter().filter(|report| return report.result.is_err()).count();
+	if failed > 0 && options.soak() {
+		return Err(Box::new(BootTestError::new(&format!(
+			"{} of {} run(s) failed", failed, reports.len()))))
+	}
+	if failed > 0 {
+		return Err(Box::new(BootTestError::new(&format!(
+			"{} of {} board(s) failed", failed, reports.len()))))
+	}
+
+	return Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::{BufRead, BufReader, Write};
+	use std::os::unix::net::UnixListener;
+
+	const CONFIG: &str = r##"
+boards:
+  careless:
+    type: mock
+    serial: "MOCK1"
+    port: "1"
+    login:
+      user: root
+      password: hunter2
+    boot:
+      steps:
+        - name: login
+          expect: "login: "
+          send: "{user}"
+          timeout: 5
+        - name: password
+          expect: "Password: "
+          send: "{password}"
+          timeout: 5
+        - name: shell
+          expect: "# "
+          fail: "Login incorrect[^\r\n]*"
+          timeout: 5
+"##;
+
+	/// A board that echoes everything sent to it, password included, then
+	/// says whatever reply does about it. Hands back the board & where its
+	/// config is, under a directory of its own.
+	fn careless(test: &str, reply: &'static str) -> (Board, PathBuf)
+	{
+		let dir = std::env::temp_dir().join(format!("lab-redact-{}-{}", test, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let config = dir.join("config.yaml");
+		fs::write(&config, CONFIG).unwrap();
+
+		let mut board = boards::get_boards_from_config(&["careless".to_string()],
+							       config.display().to_string())
+			.unwrap()
+			.remove(0);
+		let socket = dir.join("uart.sock");
+		board.primary_uart = format!("{}{}", console::SOCKET, socket.display());
+
+		let listener = UnixListener::bind(&socket).unwrap();
+		let powered_on = board.powered_on.clone();
+		thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream.try_clone().unwrap());
+			let mut writer = stream;
+			let mut line = String::new();
+
+			/* not a peep until the power is on, like the real thing */
+			while powered_on.lock().unwrap().is_none() {
+				thread::sleep(Duration::from_millis(50));
+			}
+
+			write!(writer, "careless login: ").unwrap();
+			reader.read_line(&mut line).unwrap();
+			write!(writer, "{}\r\nPassword: ", line.trim()).unwrap();
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+			write!(writer, "{}\r\n{}", line.trim(), reply.replace("{password}", line.trim())).unwrap();
+
+			/* the poweroff, if it got that far */
+			line.clear();
+			if reader.read_line(&mut line).is_ok_and(|read| return read > 0) {
+				write!(writer, "{}\r\nreboot: System halted\r\n", line.trim()).unwrap();
+			}
+		});
+
+		return (board, dir)
+	}
+
+	#[test]
+	fn password_stays_out_of_the_transcript()
+	{
+		let (board, dir) = careless("transcript", "welcome back, {password}\r\n# ");
+		let report = test(&board, &Cancel::default());
+
+		assert!(report.result.is_ok(), "{:?}", report.result);
+		let transcript = fs::read_to_string(report.transcript.unwrap()).unwrap();
+		assert!(transcript.contains("welcome back, ********"), "{}", transcript);
+		assert!(!transcript.contains("hunter2"), "{}", transcript);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn password_stays_out_of_the_report()
+	{
+		let (board, dir) = careless("report", "Login incorrect for {password}\r\n");
+		let report = test(&board, &Cancel::default());
+
+		let error = report.result.clone().unwrap_err();
+		assert!(error.contains("Login incorrect for ********"), "{}", error);
+		let json = serde_json::to_string(&report.summary()).unwrap();
+		assert!(!json.contains("hunter2"), "{}", json);
+		let transcript = fs::read_to_string(report.transcript.unwrap()).unwrap();
+		assert!(!transcript.contains("hunter2"), "{}", transcript);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+}
@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{fmt, time::Duration};
+use log::debug;
+use regex::Regex;
+use crate::config::CommandConfig;
+use crate::power::{self, Action, PowerBackend};
+
+/*
+ * Switches lab has no driver for, worked by whatever program the config says
+ * to run. The serial is only ever handed to those programs, and a switch is
+ * always there as far as lab can tell. Each board gets its status from its own
+ * command, so no two of them can be read together.
+ */
+
+#[derive(Debug)]
+pub struct CommandError {
+	details: String
+}
+
+impl CommandError {
+	pub fn new(msg: &str) -> CommandError {
+		return CommandError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for CommandError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "command failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for CommandError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+#[derive(Debug)]
+pub struct Scripted {
+	board: String,
+	serial_number: String,
+	port_number: String,
+	config: CommandConfig,
+}
+
+impl Scripted {
+	pub fn new(board: &str, serial_number: &str, port_number: &str, config: &CommandConfig)
+	-> Scripted
+	{
+		return Scripted {
+			board: board.to_string(),
+			serial_number: serial_number.to_string(),
+			port_number: port_number.to_string(),
+			config: config.clone(),
+		}
+	}
+
+	/// a command with the placeholders filled in, for the given port
+	fn argv(&self, template: &[String], port_number: &str) -> Vec<String>
+	{
+		return template
+			.iter()
+			.map(|arg| {
+				return arg
+					.replace("{board}", &self.board)
+					.replace("{serial}", &self.serial_number)
+					.replace("{port}", port_number)
+			})
+			.collect()
+	}
+
+	fn timeout(&self) -> Duration
+	{
+		return Duration::from_secs(self.config.timeout)
+	}
+
+	fn switch(&self, template: &[String]) -> Result<(), Box<dyn std::error::Error>>
+	{
+		let argv = self.argv(template, &self.port_number);
+		let output = power::execute(&argv, self.timeout()).map_err(|e| return CommandError::new(&e))?;
+
+		if !output.status.success() {
+			return Err(Box::new(CommandError::new(&power::failure(&argv, &output))));
+		}
+
+		return Ok(())
+	}
+
+	fn port_state(&self, port_number: &str) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		let argv = self.argv(&self.config.status, port_number);
+		let output = power::execute(&argv, self.timeout()).map_err(|e| return CommandError::new(&e))?;
+
+		let on = match (&self.config.on_when, output.status.code()) {
+			(Some(on_when), Some(0)) => {
+				/* checked when the config was loaded */
+				let on_when = Regex::new(on_when).unwrap();
+				on_when.is_match(&String::from_utf8_lossy(&output.stdout))
+			},
+			(None, Some(0)) => true,
+			(None, Some(1)) => false,
+			_ => return Err(Box::new(CommandError::new(&power::failure(&argv, &output)))),
+		};
+		debug!("{}@{} (command) is {}", self.serial_number, port_number, on);
+
+		return Ok(on)
+	}
+}
+
+impl PowerBackend for Scripted {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.switch(&self.config.on)
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.switch(&self.config.off)
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		return self.port_state(&self.port_number)
+	}
+
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		return Ok(vec![self.serial_number.clone()])
+	}
+
+	/// a switch of its own, boards sharing a serial or not
+	fn hub(&self) -> String
+	{
+		return format!("command:{}", self.board)
+	}
+
+	/// one run of the status command per port, there being no telling how
+	/// a vendor's script would go about several at once
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		return ports.iter().map(|port| return self.port_state(port)).collect()
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		match action {
+			Action::On => return self.argv(&self.config.on, &self.port_number).join(" "),
+			Action::Off => return self.argv(&self.config.off, &self.port_number).join(" "),
+			Action::Status => return self.argv(&self.config.status, &self.port_number).join(" "),
+			Action::List => return format!("nothing, {} is always there", self.serial_number),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use crate::boards::Board;
+	use crate::poller;
+
+	fn sh(script: &str) -> Vec<String>
+	{
+		return vec!["sh".to_string(), "-c".to_string(), script.to_string(), "sh".to_string(),
+			    "{board}".to_string(), "{serial}".to_string(), "{port}".to_string()]
+	}
+
+	fn config(status: &str, on_when: Option<&str>) -> CommandConfig
+	{
+		return CommandConfig {
+			on: sh("true"),
+			off: sh("true"),
+			status: sh(status),
+			on_when: on_when.map(|on_when| return on_when.to_string()),
+			timeout: 5,
+		}
+	}
+
+	fn board(name: &str, config: &CommandConfig) -> Board
+	{
+		return Board {
+			name: name.to_string(),
+			power_source: "command".to_string(),
+			power: Arc::new(Scripted::new(name, "n/a", "n/a", config)),
+			..Board::default()
+		}
+	}
+
+	#[test]
+	fn boards_without_a_serial_are_polled_with_their_own_status()
+	{
+		/* one status for both, told apart only by which board runs it */
+		let status = config(r#"test "$1" = a"#, None);
+		let boards = [board("a", &status), board("b", &status)];
+
+		assert_eq!(poller::by_hub(&boards).len(), 2);
+
+		let states = poller::poll(&boards);
+		assert_eq!(states["a"].powered, Some(true));
+		assert_eq!(states["b"].powered, Some(false));
+		assert!(states["b"].error.is_none());
+	}
+
+	#[test]
+	fn boards_with_different_status_commands_are_read_apart()
+	{
+		let boards = [
+			board("a", &config("exit 0", None)),
+			board("b", &config("exit 1", None)),
+		];
+
+		let states = poller::poll(&boards);
+		assert_eq!(states["a"].powered, Some(true));
+		assert_eq!(states["b"].powered, Some(false));
+	}
+
+	#[test]
+	fn placeholders_fill_in_whole_arguments()
+	{
+		let scripted = Scripted::new("it's a; board", "pdu1", "4", &config("true", None));
+
+		assert_eq!(scripted.argv(&["x{serial}-{port}".to_string(), "{board}".to_string()], "4"),
+			   vec!["xpdu1-4".to_string(), "it's a; board".to_string()]);
+	}
+
+	#[test]
+	fn on_when_reads_what_status_prints()
+	{
+		let on = Scripted::new("a", "n/a", "n/a", &config("echo state: ON", Some("state: ON")));
+		let off = Scripted::new("a", "n/a", "n/a", &config("echo state: OFF", Some("state: ON")));
+
+		assert!(on.status().unwrap());
+		assert!(!off.status().unwrap());
+	}
+
+	#[test]
+	fn failures_carry_stderr()
+	{
+		let broken = Scripted::new("a", "n/a", "n/a", &config("echo no route to host >&2; exit 3", None));
+		let error = broken.status().unwrap_err();
+
+		assert!(error.is::<CommandError>());
+		assert!(error.to_string().contains("exit status: 3"));
+		assert!(error.to_string().contains("no route to host"));
+	}
+
+	#[test]
+	fn commands_that_hang_are_killed()
+	{
+		let hangs = Scripted::new("a", "n/a", "n/a", &CommandConfig {
+			timeout: 1,
+			..config("sleep 30", None)
+		});
+		let started = std::time::Instant::now();
+		let error = hangs.status().unwrap_err();
+
+		assert!(error.is::<CommandError>());
+		assert!(error.to_string().contains("timed out after 1s"));
+		assert!(started.elapsed() < Duration::from_secs(10));
+	}
+}
@@ -0,0 +1,1337 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de::{MapAccess, Visitor}};
+use std::{
+	collections::HashMap,
+	fmt,
+	fs,
+	marker::PhantomData,
+	os::unix::fs::MetadataExt,
+	path::{Path, PathBuf},
+};
+use crate::boards::ConfigParsingError;
+use crate::command::Scripted;
+use crate::power::{Action, PowerBackend};
+
+pub const UART_BY_ID: &str = "/dev/serial/by-id";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+	/// where the config itself was read from
+	#[serde(skip)]
+	pub path: PathBuf,
+	/// yaml file of passwords, only readable by its owner, that
+	/// "password: {secret: name}" looks things up in
+	pub secrets: Option<PathBuf>,
+	/// boot tests shared between boards, referred to by name
+	#[serde(default)]
+	pub profiles: HashMap<String, BootProfile>,
+	/// where boot test transcripts go & how many to hang on to
+	#[serde(default)]
+	pub transcripts: TranscriptConfig,
+	/// how long boot stages are expected to take
+	#[serde(default)]
+	pub baselines: BaselineConfig,
+	/// named lists of boards, for picking out a bunch of them at once
+	#[serde(default)]
+	pub groups: HashMap<String, Vec<String>>,
+	/// where "type: mock" boards keep the state of their pretend ports
+	#[serde(default)]
+	pub mock: MockConfig,
+	/// boards on the one hub that a boot test powers up at a time, to keep
+	/// the inrush down, with 0 meaning as many as there are
+	#[serde(default = "default_max_per_hub")]
+	pub max_per_hub: usize,
+	/// kept in the order they appear in the file, which is the order the
+	/// boards get shown in
+	#[serde(deserialize_with = "in_file_order")]
+	pub boards: Vec<(String, BoardConfig)>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardConfig {
+	#[serde(rename = "type")]
+	pub power_source: String,
+	/// left out for boards that hang off no hub at all, like QEMU ones
+	#[serde(default = "not_applicable")]
+	pub serial: String,
+	#[serde(default = "not_applicable")]
+	pub port: String,
+	/// how to reach the switch, defaulting to Yepkit's own tools
+	#[serde(default = "default_driver")]
+	pub driver: String,
+	pub uart: Option<UartConfig>,
+	/// how to start a "type: qemu" board, which has no uart of its own
+	pub qemu: Option<QemuConfig>,
+	/// what a "type: sim" board, played back by lab sim, gets up to
+	pub sim: Option<SimConfig>,
+	/// what a "type: command" board runs to switch it
+	pub command: Option<CommandConfig>,
+	/// which PDU outlet a "type: snmp" board is on, and how to get at it
+	pub snmp: Option<SnmpConfig>,
+	/// name of a shared profile to take the boot test from
+	pub profile: Option<String>,
+	/// a boot test of the board's own, instead of using a profile
+	pub boot: Option<BootProfile>,
+	/// overrides the login of the board's profile
+	pub login: Option<LoginConfig>,
+	/// seconds goodnight waits for the board to shut itself down before
+	/// cutting the power regardless
+	#[serde(default = "default_shutdown_timeout")]
+	pub shutdown_timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootProfile {
+	pub login: Option<LoginConfig>,
+	pub steps: Vec<BootStep>,
+}
+
+/// Who to log in as, which boot steps get at as {user} and {password} in
+/// what they send.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoginConfig {
+	#[serde(default = "default_user")]
+	pub user: String,
+	pub password: Option<PasswordConfig>,
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PasswordConfig {
+	Value(String),
+	Env {
+		env: String,
+	},
+	Secret {
+		secret: String,
+	},
+}
+
+/* never let a password near a log */
+impl fmt::Debug for PasswordConfig {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PasswordConfig::Value(_) => return write!(f, "Value(********)"),
+			PasswordConfig::Env { env } => return write!(f, "Env({})", env),
+			PasswordConfig::Secret { secret } => return write!(f, "Secret({})", secret),
+		}
+	}
+}
+
+/// A password, once it has been looked up. Only expose() gets at it.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn expose(&self) -> &str
+	{
+		return &self.0
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "********")
+	}
+}
+
+/// A board's login, with everything needed to look the password up
+/// whenever it is wanted rather than when the config gets loaded.
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+	pub user: String,
+	password: Option<PasswordConfig>,
+	secrets: Option<PathBuf>,
+}
+
+/// One step of a boot test: wait for expect to match, then send a line.
+/// Either can be left out, but not both.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootStep {
+	/// what to call the step in output, defaulting to the pattern
+	pub name: Option<String>,
+	pub expect: Option<String>,
+	pub send: Option<String>,
+	/// seconds to wait for expect to match
+	#[serde(default = "default_step_timeout")]
+	pub timeout: u64,
+	/// give up on the boot test straight away if this matches first
+	pub fail: Option<String>,
+	/// a sign the board is still on its way, so keep waiting from scratch
+	pub retry: Option<String>,
+}
+
+/* what every board got before boot tests were configurable */
+const DEFAULT_PROFILE: &str = r#"
+login:
+  user: root
+  password: fedora_rocks!
+steps:
+  - name: U-Boot
+    expect: ".*U-Boot.*"
+  - name: Linux
+    expect: ".*Linux version.*"
+    retry: ".*Hit any key to stop autoboot.*"
+  - name: init
+    expect: ".*init.*"
+  - name: login
+    expect: ".*login: .*"
+    send: "{user}"
+  - name: password
+    expect: ".*assword: "
+    send: "{password}"
+  - name: shell
+    expect: ".*#.*"
+"#;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TranscriptConfig {
+	/// relative to the config, with a directory per board underneath
+	#[serde(default = "default_transcript_dir")]
+	pub dir: PathBuf,
+	/// runs kept per board, the oldest going first
+	#[serde(default = "default_transcript_keep")]
+	pub keep: usize,
+}
+
+impl Default for TranscriptConfig {
+	fn default() -> TranscriptConfig
+	{
+		return TranscriptConfig {
+			dir: default_transcript_dir(),
+			keep: default_transcript_keep(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BaselineConfig {
+	/// relative to the config, written by boot-test --save-baseline
+	#[serde(default = "default_baseline_file")]
+	pub file: PathBuf,
+	/// how much slower than its baseline a stage may get, in percent
+	#[serde(default = "default_tolerance")]
+	pub tolerance: f64,
+	/// seconds allowed on top, so stages that are over in a flash don't
+	/// fail on a bit of jitter
+	#[serde(default = "default_slack")]
+	pub slack: f64,
+}
+
+impl Default for BaselineConfig {
+	fn default() -> BaselineConfig
+	{
+		return BaselineConfig {
+			file: default_baseline_file(),
+			tolerance: default_tolerance(),
+			slack: default_slack(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockConfig {
+	/// relative to the config, created on first use
+	#[serde(default = "default_mock_file")]
+	pub file: PathBuf,
+}
+
+impl Default for MockConfig {
+	fn default() -> MockConfig
+	{
+		return MockConfig {
+			file: default_mock_file(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UartConfig {
+	/// the part of the /dev/serial/by-id name shared by all of the ports
+	pub pattern: String,
+	pub primary: String,
+	pub bootloader: Option<String>,
+}
+
+/// A virtual board, powered on by starting QEMU and off by killing it.
+/// Its uart is QEMU's first serial port, which lab adds to the command as a
+/// unix socket so that it keeps the same path from one boot to the next.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QemuConfig {
+	/// program & arguments, without any -serial, which lab adds itself
+	pub command: Vec<String>,
+	/// relative to the config, where the socket, pidfile and QEMU's own
+	/// output go
+	#[serde(default = "default_qemu_dir")]
+	pub dir: PathBuf,
+}
+
+/// A simulated board, which lab sim plays a boot log back for on a pty
+/// whenever it gets powered on, and then lets log in.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimConfig {
+	/// relative to the config, a boot log to play back, a transcript's
+	/// timings included, rather than the built-in one
+	pub log: Option<PathBuf>,
+	/// milliseconds between lines that don't come with a time of their own
+	#[serde(default = "default_sim_delay")]
+	pub delay: u64,
+	/// how much faster than it was recorded to play a transcript back
+	#[serde(default = "default_sim_speed")]
+	pub speed: f64,
+	/// something to go wrong along the way
+	pub fault: Option<FaultConfig>,
+	/// relative to the config, where the pty and power state go
+	#[serde(default = "default_sim_dir")]
+	pub dir: PathBuf,
+}
+
+/// A board switched by some other program, a vendor's script say. Each
+/// command is argv, with {board}, {serial} and {port} filled in within the
+/// arguments they appear in. No shell gets involved, so none of the quoting
+/// or expansion of one happens either.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandConfig {
+	pub on: Vec<String>,
+	pub off: Vec<String>,
+	pub status: Vec<String>,
+	/// matched against what status prints, the board being on if it
+	/// matches. Without one, status exiting 0 means on, 1 off & anything
+	/// else that it failed.
+	pub on_when: Option<String>,
+	/// seconds any of the commands get before being killed
+	#[serde(default = "default_command_timeout")]
+	pub timeout: u64,
+}
+
+pub const PLACEHOLDERS: [&str; 3] = ["{board}", "{serial}", "{port}"];
+
+/// A board on an outlet of a switched PDU, worked with net-snmp's snmpget &
+/// snmpset. The board's serial & port are the PDU & outlet, which is how the
+/// boards on one PDU get their state read in a single request.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnmpConfig {
+	/// the PDU, with a port if it isn't listening on 161, like localhost:1161
+	pub host: String,
+	pub outlet: u32,
+	#[serde(default)]
+	pub version: SnmpVersion,
+	/// for v2c, the community with write access
+	pub community: Option<PasswordConfig>,
+	/// for v3, who to be, with an auth key for authNoPriv and a privacy
+	/// key as well for authPriv
+	pub user: Option<String>,
+	pub auth: Option<SnmpKey>,
+	pub privacy: Option<SnmpKey>,
+	/// where the outlet's control & state are, for PDUs that aren't APCs
+	#[serde(default)]
+	pub oids: OutletOids,
+	/// seconds to wait for the PDU to answer, which it gets a retry of
+	#[serde(default = "default_snmp_timeout")]
+	pub timeout: u64,
+	#[serde(skip)]
+	secrets: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum SnmpVersion {
+	#[default]
+	#[serde(rename = "2c")]
+	V2c,
+	#[serde(rename = "3")]
+	V3,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnmpKey {
+	/// as snmpget's -a or -x take it, like SHA or AES
+	pub protocol: String,
+	pub password: PasswordConfig,
+}
+
+/// The outlet's number goes on the end of both OIDs. An outlet is on when
+/// its state reads as powered, and gets switched by setting its control to
+/// on, off or, if the PDU can do it itself, reboot.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OutletOids {
+	pub control: String,
+	pub state: String,
+	pub on: i64,
+	pub off: i64,
+	pub reboot: Option<i64>,
+	pub powered: i64,
+}
+
+/* PowerNet-MIB's rPDUOutletControlOutletCommand & rPDUOutletStatusOutletState */
+impl Default for OutletOids {
+	fn default() -> OutletOids {
+		return OutletOids {
+			control: ".1.3.6.1.4.1.318.1.1.12.3.3.1.1.4".to_string(),
+			state: ".1.3.6.1.4.1.318.1.1.12.3.5.1.1.4".to_string(),
+			on: 1,
+			off: 2,
+			reboot: Some(3),
+			powered: 1,
+		}
+	}
+}
+
+const SNMP_AUTH: [&str; 6] = ["MD5", "SHA", "SHA-224", "SHA-256", "SHA-384", "SHA-512"];
+const SNMP_PRIVACY: [&str; 4] = ["DES", "AES", "AES-192", "AES-256"];
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fault {
+	/// a kernel panic, and then nothing
+	Panic,
+	/// just nothing
+	Hang,
+	/// output carries on, but with bytes mangled like at the wrong baud rate
+	Garble,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultConfig {
+	pub kind: Fault,
+	/// the fault hits in place of the first line this matches, or straight
+	/// away if left out
+	pub at: Option<String>,
+}
+
+fn not_applicable() -> String
+{
+	return "n/a".to_string()
+}
+
+fn default_driver() -> String
+{
+	return "ykcmd".to_string()
+}
+
+fn default_step_timeout() -> u64
+{
+	return 120
+}
+
+fn default_shutdown_timeout() -> u64
+{
+	return 60
+}
+
+fn default_user() -> String
+{
+	return "root".to_string()
+}
+
+fn default_qemu_dir() -> PathBuf
+{
+	return PathBuf::from("run")
+}
+
+fn default_sim_delay() -> u64
+{
+	return 50
+}
+
+fn default_sim_speed() -> f64
+{
+	return 1.0
+}
+
+fn default_sim_dir() -> PathBuf
+{
+	return PathBuf::from("run")
+}
+
+fn default_command_timeout() -> u64
+{
+	return 30
+}
+
+fn default_snmp_timeout() -> u64
+{
+	return 5
+}
+
+fn default_mock_file() -> PathBuf
+{
+	return PathBuf::from("run/mock.yaml")
+}
+
+fn default_transcript_dir() -> PathBuf
+{
+	return PathBuf::from("transcripts")
+}
+
+fn default_transcript_keep() -> usize
+{
+	return 20
+}
+
+fn default_max_per_hub() -> usize
+{
+	return 1
+}
+
+fn default_baseline_file() -> PathBuf
+{
+	return PathBuf::from("baselines.json")
+}
+
+fn default_tolerance() -> f64
+{
+	return 10.0
+}
+
+fn default_slack() -> f64
+{
+	return 0.5
+}
+
+/// a password anyone else can read is not much of a password
+fn check_secrets_file(file: &fs::File, secrets: &Path) -> Result<(), String>
+{
+	let metadata = file.metadata().map_err(|e| return e.to_string())?;
+
+	if metadata.mode() & 0o077 != 0 || metadata.uid() != unsafe { libc::getuid() } {
+		return Err(format!("{} must be owned by you and have mode 0600, refusing to read it",
+				   secrets.display()))
+	}
+
+	return Ok(())
+}
+
+fn read_secret(secrets: &Path, name: &str) -> Result<Secret, Box<dyn std::error::Error>>
+{
+	let file = fs::File::open(secrets)
+		.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", secrets.display(), e)))?;
+	check_secrets_file(&file, secrets).map_err(|e| return ConfigParsingError::new(&e))?;
+
+	let all: HashMap<String, String> = serde_yaml::from_reader(file)
+		.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", secrets.display(), e)))?;
+	let secret = all
+		.get(name)
+		.ok_or_else(|| {
+			return ConfigParsingError::new(&format!("{} has no secret called {}",
+							     secrets.display(), name))
+		})?;
+
+	return Ok(Secret(secret.clone()))
+}
+
+impl PasswordConfig {
+	fn look_up(&self, secrets: Option<&Path>) -> Result<Secret, Box<dyn std::error::Error>>
+	{
+		match self {
+			PasswordConfig::Value(password) => return Ok(Secret(password.clone())),
+			PasswordConfig::Env { env } => {
+				let password = std::env::var(env).map_err(|_| {
+					return ConfigParsingError::new(&format!("password variable {} is not set", env))
+				})?;
+				return Ok(Secret(password))
+			},
+			PasswordConfig::Secret { secret } => {
+				let secrets = secrets
+					.ok_or_else(|| return ConfigParsingError::new("no secrets file configured"))?;
+				return read_secret(secrets, secret)
+			},
+		}
+	}
+}
+
+impl Credentials {
+	pub fn password(&self) -> Result<Secret, Box<dyn std::error::Error>>
+	{
+		match &self.password {
+			None => return Err(Box::new(ConfigParsingError::new("no password configured"))),
+			Some(password) => return password.look_up(self.secrets.as_deref()),
+		}
+	}
+}
+
+impl SnmpConfig {
+	/// the v2c community, looked up only once it is wanted like passwords
+	pub fn community(&self) -> Result<Secret, Box<dyn std::error::Error>>
+	{
+		match &self.community {
+			None => return Err(Box::new(ConfigParsingError::new("no community configured"))),
+			Some(community) => return community.look_up(self.secrets.as_deref()),
+		}
+	}
+
+	pub fn key(&self, key: &SnmpKey) -> Result<Secret, Box<dyn std::error::Error>>
+	{
+		return key.password.look_up(self.secrets.as_deref())
+	}
+
+	/// whether outlets on the PDU get read the same way as through other,
+	/// secrets & all, so that they can be asked after together
+	pub fn reads_like(&self, other: &SnmpConfig) -> bool
+	{
+		return self.version == other.version
+			&& self.community == other.community
+			&& self.user == other.user
+			&& self.auth == other.auth
+			&& self.privacy == other.privacy
+			&& self.oids.state == other.oids.state
+			&& self.oids.powered == other.oids.powered
+	}
+}
+
+impl BootStep {
+	pub fn label(&self) -> String
+	{
+		if let Some(name) = &self.name {
+			return name.clone()
+		}
+
+		match (&self.expect, &self.send) {
+			(Some(expect), _) => return expect.clone(),
+			(None, Some(send)) => return format!("send {}", send),
+			(None, None) => return "nothing".to_string(),
+		}
+	}
+
+	fn validate(&self) -> Result<(), String>
+	{
+		if self.expect.is_none() && self.send.is_none() {
+			return Err(format!("step {} neither expects nor sends anything", self.label()))
+		}
+
+		for pattern in self.expect.iter().chain(self.fail.iter()).chain(self.retry.iter()) {
+			Regex::new(pattern).map_err(|e| return format!("step {}: {}", self.label(), e))?;
+		}
+
+		return Ok(())
+	}
+}
+
+impl Config {
+	/// paths in the config are relative to wherever the config is
+	fn relative(&self, path: &Path) -> PathBuf
+	{
+		return self.path.parent().unwrap_or_else(|| return Path::new(".")).join(path)
+	}
+
+	pub fn baselines(&self) -> BaselineConfig
+	{
+		return BaselineConfig {
+			file: self.relative(&self.baselines.file),
+			..self.baselines.clone()
+		}
+	}
+
+	/// a board's QEMU setup, with its directory resolved
+	pub fn qemu(&self, board: &BoardConfig) -> Option<QemuConfig>
+	{
+		return board.qemu.as_ref().map(|qemu| {
+			return QemuConfig {
+				dir: self.relative(&qemu.dir),
+				..qemu.clone()
+			}
+		})
+	}
+
+	/// a board's simulation, with its paths resolved
+	pub fn sim(&self, board: &BoardConfig) -> Option<SimConfig>
+	{
+		return board.sim.as_ref().map(|sim| {
+			return SimConfig {
+				log: sim.log.as_ref().map(|log| return self.relative(log)),
+				dir: self.relative(&sim.dir),
+				..sim.clone()
+			}
+		})
+	}
+
+	/// a board's PDU outlet, able to get at the secrets file
+	pub fn snmp(&self, board: &BoardConfig) -> Option<SnmpConfig>
+	{
+		return board.snmp.as_ref().map(|snmp| {
+			return SnmpConfig {
+				secrets: self.secrets.as_ref().map(|secrets| return self.relative(secrets)),
+				..snmp.clone()
+			}
+		})
+	}
+
+	pub fn mock(&self) -> MockConfig
+	{
+		return MockConfig {
+			file: self.relative(&self.mock.file),
+		}
+	}
+
+	pub fn transcripts(&self) -> TranscriptConfig
+	{
+		return TranscriptConfig {
+			dir: self.relative(&self.transcripts.dir),
+			keep: self.transcripts.keep,
+		}
+	}
+
+	/// the boot test for a board, falling back to the default profile
+	pub fn boot_profile(&self, board: &BoardConfig) -> Result<BootProfile, String>
+	{
+		match (&board.profile, &board.boot) {
+			(Some(_), Some(_)) => return Err("has both a profile and a boot test".to_string()),
+			(Some(name), None) => {
+				let profile = self.profiles
+					.get(name)
+					.ok_or_else(|| return format!("no profile called {}", name))?;
+				return Ok(profile.clone())
+			},
+			(None, Some(profile)) => return Ok(profile.clone()),
+			(None, None) => {
+				let profile: BootProfile = serde_yaml::from_str(DEFAULT_PROFILE)
+					.map_err(|e| return e.to_string())?;
+				return Ok(profile)
+			},
+		}
+	}
+
+	pub fn boot_steps(&self, board: &BoardConfig) -> Result<Vec<BootStep>, String>
+	{
+		return Ok(self.boot_profile(board)?.steps)
+	}
+
+	/// the board's own login if it has one, otherwise its profile's
+	pub fn credentials(&self, board: &BoardConfig) -> Result<Credentials, String>
+	{
+		let login = match &board.login {
+			Some(login) => Some(login.clone()),
+			None => self.boot_profile(board)?.login,
+		};
+
+		let secrets = self.secrets.as_ref().map(|secrets| return self.relative(secrets));
+
+		match login {
+			Some(login) => return Ok(Credentials {
+				user: login.user,
+				password: login.password,
+				secrets,
+			}),
+			None => return Ok(Credentials {
+				user: default_user(),
+				password: None,
+				secrets,
+			}),
+		}
+	}
+}
+
+impl UartConfig {
+	pub fn path(&self, port: &str) -> String
+	{
+		return format!("{}/{}-{}", UART_BY_ID, self.pattern, port)
+	}
+}
+
+fn in_file_order<'de, D, T>(deserializer: D) -> Result<Vec<(String, T)>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	struct OrderedVisitor<T>(PhantomData<T>);
+
+	impl<'de, T: Deserialize<'de>> Visitor<'de> for OrderedVisitor<T> {
+		type Value = Vec<(String, T)>;
+
+		fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			return write!(f, "a map of board names to boards")
+		}
+
+		fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error>
+		{
+			let mut entries = Vec::new();
+
+			while let Some(entry) = map.next_entry()? {
+				entries.push(entry);
+			}
+
+			return Ok(entries)
+		}
+	}
+
+	return deserializer.deserialize_map(OrderedVisitor(PhantomData))
+}
+
+/// Serials and ports end up as arguments to external tools, so only allow
+/// what a Yepkit serial or port could actually look like. "n/a" is left
+/// through for boards that are not hooked up to anything yet.
+fn validate_field(board: &str, what: &str, value: &str) -> Result<(), Box<dyn std::error::Error>>
+{
+	if value == "n/a" {
+		return Ok(())
+	}
+
+	if value.is_empty() || value.len() > 32
+	   || !value.chars().all(|c| return c.is_ascii_alphanumeric()) {
+		return Err(Box::new(ConfigParsingError::new(&format!(
+			"{}: {} \"{}\" may only contain letters and digits", board, what, value))))
+	}
+
+	return Ok(())
+}
+
+/// QEMU & simulated boards get everything from a section of their own, which
+/// other boards must not have
+fn validate_virtual(board: &BoardConfig) -> Result<(), String>
+{
+	let sections = [("qemu", board.qemu.is_some()), ("sim", board.sim.is_some())];
+
+	for (kind, present) in sections {
+		match (board.power_source == kind, present) {
+			(true, false) => return Err(format!("{} board has no {} section", kind, kind)),
+			(false, true) => {
+				return Err(format!("{} board has a {} section", board.power_source, kind))
+			},
+			(true, true) => {
+				if board.uart.is_some() {
+					return Err(format!("{} board has a uart, it brings its own", kind))
+				}
+				if board.serial != "n/a" || board.port != "n/a" {
+					return Err(format!("{} board has a serial or port set", kind))
+				}
+			},
+			(false, false) => (),
+		}
+	}
+
+	if board.qemu.as_ref().is_some_and(|qemu| return qemu.command.is_empty()) {
+		return Err("qemu command is empty".to_string())
+	}
+
+	if let Some(sim) = &board.sim {
+		if sim.speed <= 0.0 {
+			return Err("sim speed must be more than 0".to_string())
+		}
+		if let Some(at) = sim.fault.as_ref().and_then(|fault| return fault.at.as_ref()) {
+			Regex::new(at).map_err(|e| return format!("sim fault: {}", e))?;
+		}
+	}
+
+	return Ok(())
+}
+
+/// A "type: command" board needs commands, which must not ask for anything
+/// lab doesn't know how to fill in
+fn validate_command(board: &BoardConfig) -> Result<(), String>
+{
+	let command = match (board.power_source == "command", &board.command) {
+		(true, Some(command)) => command,
+		(true, None) => return Err("command board has no command section".to_string()),
+		(false, Some(_)) => {
+			return Err(format!("{} board has a command section", board.power_source))
+		},
+		(false, None) => return Ok(()),
+	};
+
+	let placeholder = Regex::new(r"\{[^{}]*\}").unwrap();
+	for (what, argv) in [("on", &command.on), ("off", &command.off), ("status", &command.status)] {
+		if argv.is_empty() {
+			return Err(format!("{} command is empty", what))
+		}
+		for arg in argv.iter() {
+			if let Some(unknown) = placeholder.find_iter(arg)
+				.find(|found| return !PLACEHOLDERS.contains(&found.as_str())) {
+				return Err(format!("{} command: no such placeholder as {}, only {}",
+						   what, unknown.as_str(), PLACEHOLDERS.join(", ")))
+			}
+		}
+	}
+
+	if let Some(on_when) = &command.on_when {
+		Regex::new(on_when).map_err(|e| return format!("command on_when: {}", e))?;
+	}
+
+	return Ok(())
+}
+
+/// A "type: snmp" board needs an outlet with what it takes to reach it, all
+/// of which end up as snmpget's arguments
+fn validate_snmp(board: &BoardConfig) -> Result<(), String>
+{
+	let snmp = match (board.power_source == "snmp", &board.snmp) {
+		(true, Some(snmp)) => snmp,
+		(true, None) => return Err("snmp board has no snmp section".to_string()),
+		(false, Some(_)) => {
+			return Err(format!("{} board has a snmp section", board.power_source))
+		},
+		(false, None) => return Ok(()),
+	};
+
+	if board.serial != "n/a" || board.port != "n/a" {
+		return Err("snmp board has a serial or port set, it has a host & outlet".to_string())
+	}
+
+	/* nothing that snmpget could take for an option */
+	let host = Regex::new(r"^[[:alnum:]\[][[:alnum:].:\[\]-]*$").unwrap();
+	if !host.is_match(&snmp.host) {
+		return Err(format!("snmp host \"{}\" is not a host name or address", snmp.host))
+	}
+
+	let oid = Regex::new(r"^\.?[0-9]+(\.[0-9]+)*$").unwrap();
+	for (what, value) in [("control", &snmp.oids.control), ("state", &snmp.oids.state)] {
+		if !oid.is_match(value) {
+			return Err(format!("snmp {} \"{}\" is not a numeric OID", what, value))
+		}
+	}
+
+	match snmp.version {
+		SnmpVersion::V2c => {
+			if snmp.community.is_none() {
+				return Err("snmp v2c needs a community".to_string())
+			}
+			if snmp.user.is_some() || snmp.auth.is_some() || snmp.privacy.is_some() {
+				return Err("snmp v2c has a community, not a user or keys".to_string())
+			}
+		},
+		SnmpVersion::V3 => {
+			if snmp.community.is_some() {
+				return Err("snmp v3 has a user & keys, not a community".to_string())
+			}
+			let user = snmp.user.as_ref().ok_or("snmp v3 needs a user")?;
+			if user.is_empty() || user.starts_with('-') {
+				return Err(format!("snmp user \"{}\" is not a user", user))
+			}
+			if snmp.privacy.is_some() && snmp.auth.is_none() {
+				return Err("snmp privacy needs auth as well".to_string())
+			}
+		},
+	}
+
+	for (what, key, protocols) in [("auth", &snmp.auth, &SNMP_AUTH[..]),
+				       ("privacy", &snmp.privacy, &SNMP_PRIVACY[..])] {
+		if let Some(key) = key.as_ref().filter(|key| return !protocols.contains(&key.protocol.as_str())) {
+			return Err(format!("snmp {} protocol {} is not one of {}",
+					   what, key.protocol, protocols.join(", ")))
+		}
+	}
+
+	return Ok(())
+}
+
+pub fn load(input_file: &str) -> Result<Config, Box<dyn std::error::Error>>
+{
+	let contents = fs::read_to_string(input_file)
+		.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", input_file, e)))?;
+
+	/* serde_yaml's errors already carry the line & column */
+	let mut config: Config = serde_yaml::from_str(&contents)
+		.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", input_file, e)))?;
+	config.path = PathBuf::from(input_file);
+
+	for (name, board) in config.boards.iter() {
+		validate_field(name, "serial", &board.serial)?;
+		validate_field(name, "port", &board.port)?;
+		validate_virtual(board)
+			.and_then(|_| return validate_command(board))
+			.and_then(|_| return validate_snmp(board))
+			.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", name, e)))?;
+
+		let steps = config.boot_steps(board)
+			.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", name, e)))?;
+		for step in steps.iter() {
+			step.validate().map_err(|e| {
+				return ConfigParsingError::new(&format!("{}: {}", name, e))
+			})?;
+		}
+	}
+
+	for (name, profile) in config.profiles.iter() {
+		for step in profile.steps.iter() {
+			step.validate().map_err(|e| {
+				return ConfigParsingError::new(&format!("profile {}: {}", name, e))
+			})?;
+		}
+	}
+
+	for (name, members) in config.groups.iter() {
+		for member in members.iter() {
+			if !config.boards.iter().any(|(board, _)| return board == member) {
+				return Err(Box::new(ConfigParsingError::new(&format!(
+					"group {}: no board called {}", name, member))))
+			}
+		}
+	}
+
+	return Ok(config)
+}
+
+/// whether a program can be run, be it by path or from somewhere on $PATH
+pub fn on_path(program: &str) -> bool
+{
+	if program.contains('/') {
+		return Path::new(program).is_file()
+	}
+
+	let path = std::env::var_os("PATH").unwrap_or_default();
+	return std::env::split_paths(&path).any(|dir| return dir.join(program).is_file())
+}
+
+/// Things that parse fine but are still likely to be mistakes
+pub fn problems(config: &Config) -> Vec<String>
+{
+	let mut problems = Vec::new();
+	let by_id: Vec<String> = match fs::read_dir(UART_BY_ID) {
+		Ok(entries) => entries
+			.filter_map(|entry| return entry.ok())
+			.map(|entry| return entry.file_name().to_string_lossy().into_owned())
+			.collect(),
+		Err(_) => Vec::new(),
+	};
+
+	if let Some(secrets) = &config.secrets {
+		let secrets = config.relative(secrets);
+		let checked = fs::File::open(&secrets)
+			.map_err(|e| return format!("{}: {}", secrets.display(), e))
+			.and_then(|file| return check_secrets_file(&file, &secrets));
+		if let Err(e) = checked {
+			problems.push(e);
+		}
+	}
+
+	for (i, (name, board)) in config.boards.iter().enumerate() {
+		if let Some(qemu) = config.qemu(board) {
+			if !on_path(&qemu.command[0]) {
+				problems.push(format!("{}: {} not found", name, qemu.command[0]));
+			}
+			continue;
+		}
+
+		if let Some(sim) = config.sim(board) {
+			if let Some(log) = sim.log.filter(|log| return !log.is_file()) {
+				problems.push(format!("{}: sim log {} does not exist", name, log.display()));
+			}
+			continue;
+		}
+
+		if let Some(command) = &board.command {
+			let mut programs = vec![&command.on[0], &command.off[0], &command.status[0]];
+			programs.sort();
+			programs.dedup();
+			for program in programs.into_iter().filter(|program| return !on_path(program)) {
+				problems.push(format!("{}: {} not found", name, program));
+			}
+			/* n/a serials & all, what tells boards apart is what ends up being run */
+			let switch = Scripted::new(name, &board.serial, &board.port, command);
+			if let Some((other, _)) = config.boards[..i]
+				.iter()
+				.find(|(other_name, other)| {
+					return other.command.as_ref().is_some_and(|command| {
+						let other = Scripted::new(other_name, &other.serial, &other.port, command);
+						return [Action::On, Action::Off]
+							.into_iter()
+							.any(|action| return other.explain(action) == switch.explain(action))
+					})
+				}) {
+				problems.push(format!("{}: switched by the same command as {}", name, other));
+			}
+		} else if let Some(snmp) = &board.snmp {
+			for tool in ["snmpget", "snmpset"].into_iter().filter(|tool| return !on_path(tool)) {
+				problems.push(format!("{}: {} not found", name, tool));
+			}
+			if let Some((other, _)) = config.boards[..i]
+				.iter()
+				.find(|(_, other)| {
+					return other.snmp.as_ref().is_some_and(|other| {
+						return other.host == snmp.host && other.outlet == snmp.outlet
+					})
+				}) {
+				problems.push(format!("{}: {} outlet {} is already used by {}",
+						      name, snmp.host, snmp.outlet, other));
+			}
+			if let Some((other, _)) = config.boards[..i]
+				.iter()
+				.find(|(_, other)| {
+					return other.snmp.as_ref().is_some_and(|other| {
+						return other.host == snmp.host && !other.reads_like(snmp)
+					})
+				}) {
+				problems.push(format!("{}: {} is reached with other settings by {}",
+						      name, snmp.host, other));
+			}
+		} else if board.serial == "n/a" || board.port == "n/a" {
+			problems.push(format!("{}: {} board has no serial or port set",
+					      name, board.power_source));
+		} else if let Some((other, _)) = config.boards[..i]
+			.iter()
+			.find(|(_, other)| {
+				return other.serial == board.serial && other.port == board.port
+			}) {
+			problems.push(format!("{}: hub {} port {} is already used by {}",
+					      name, board.serial, board.port, other));
+		}
+
+		let uart = match &board.uart {
+			Some(uart) => uart,
+			None => continue,
+		};
+
+		if !by_id.iter().any(|entry| return entry.starts_with(&uart.pattern)) {
+			problems.push(format!("{}: no uart matching {} under {}",
+					      name, uart.pattern, UART_BY_ID));
+			continue;
+		}
+
+		let mut ports = vec![&uart.primary];
+		ports.extend(uart.bootloader.iter());
+		for port in ports {
+			if !Path::new(&uart.path(port)).exists() {
+				problems.push(format!("{}: uart {} does not exist", name, uart.path(port)));
+			}
+		}
+	}
+
+	return problems
+}
+
+pub fn check(input_file: String) -> Result<(), Box<dyn std::error::Error>>
+{
+	let config = load(&input_file)?;
+	let problems = problems(&config);
+
+	for problem in problems.iter() {
+		println!("{}", problem);
+	}
+
+	if !problems.is_empty() {
+		return Err(Box::new(ConfigParsingError::new(&format!(
+			"{}: {} problem(s) found", input_file, problems.len()))))
+	}
+
+	println!("{}: ok", input_file);
+	return Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::PermissionsExt;
+
+	fn scratch(test: &str) -> PathBuf
+	{
+		let dir = std::env::temp_dir().join(format!("lab-config-{}-{}", test, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		return dir
+	}
+
+	fn secrets(dir: &Path, mode: u32) -> PathBuf
+	{
+		let path = dir.join("secrets.yaml");
+		fs::write(&path, "board: hunter2\n").unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+
+		return path
+	}
+
+	/// load a config from text, as lab would from a file
+	fn loaded(test: &str, yaml: &str) -> Result<Config, Box<dyn std::error::Error>>
+	{
+		let dir = scratch(test);
+		let path = dir.join("config.yaml");
+		fs::write(&path, yaml).unwrap();
+		let config = load(&path.display().to_string());
+		let _ = fs::remove_dir_all(dir);
+
+		return config
+	}
+
+	fn secret(secret: &str) -> PasswordConfig
+	{
+		return PasswordConfig::Secret { secret: secret.to_string() }
+	}
+
+	#[test]
+	fn secrets_only_the_owner_can_read_are_read()
+	{
+		let dir = scratch("secrets-private");
+		let path = secrets(&dir, 0o600);
+
+		assert_eq!(secret("board").look_up(Some(&path)).unwrap().expose(), "hunter2");
+		let error = secret("other").look_up(Some(&path)).unwrap_err().to_string();
+		assert!(error.contains("no secret called other"), "{}", error);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn secrets_others_can_read_are_refused()
+	{
+		let dir = scratch("secrets-readable");
+
+		for mode in [0o640, 0o604, 0o660, 0o644] {
+			let path = secrets(&dir, mode);
+			let error = secret("board").look_up(Some(&path)).unwrap_err().to_string();
+
+			assert!(error.contains("must be owned by you and have mode 0600"), "{:o}: {}", mode, error);
+			assert!(!error.contains("hunter2"));
+		}
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn secrets_owned_by_someone_else_are_refused()
+	{
+		let dir = scratch("secrets-owner");
+		let path = secrets(&dir, 0o600);
+
+		/* only root gets to give a file away */
+		if std::os::unix::fs::chown(&path, Some(unsafe { libc::getuid() } + 1), None).is_err() {
+			let _ = fs::remove_dir_all(dir);
+			return;
+		}
+
+		let error = secret("board").look_up(Some(&path)).unwrap_err().to_string();
+		assert!(error.contains("must be owned by you"), "{}", error);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn passwords_come_from_the_environment()
+	{
+		let name = format!("LAB_TEST_PASSWORD_{}", std::process::id());
+		let password = PasswordConfig::Env { env: name.clone() };
+
+		let error = password.look_up(None).unwrap_err().to_string();
+		assert!(error.contains(&format!("password variable {} is not set", name)), "{}", error);
+
+		std::env::set_var(&name, "hunter2");
+		assert_eq!(password.look_up(None).unwrap().expose(), "hunter2");
+		std::env::remove_var(&name);
+	}
+
+	#[test]
+	fn passwords_are_kept_out_of_debug_output()
+	{
+		let login: LoginConfig = serde_yaml::from_str("{user: root, password: hunter2}").unwrap();
+		let looked_up = login.password.as_ref().unwrap().look_up(None).unwrap();
+
+		assert!(!format!("{:?}", login).contains("hunter2"));
+		assert!(!format!("{:?}", looked_up).contains("hunter2"));
+	}
+
+	#[test]
+	fn unknown_fields_are_refused_with_where_they_are()
+	{
+		let yaml = "\
+boards:
+  icicle:
+    type: relay
+    serial: \"YK21614\"
+    port: \"r\"
+    uart:
+      pattern: \"usb-FAKE\"
+      primray: \"if01-port0\"
+";
+		let error = loaded("unknown-field", yaml).unwrap_err().to_string();
+
+		assert!(error.contains("unknown field `primray`"), "{}", error);
+		assert!(error.contains("line 8 column"), "{}", error);
+	}
+
+	#[test]
+	fn serials_and_ports_are_letters_and_digits()
+	{
+		for good in ["YK21614", "r", "n/a"] {
+			assert!(validate_field("board", "serial", good).is_ok(), "{}", good);
+		}
+		for bad in ["", "-l", "YK 1", "YK1;reboot", &"9".repeat(33)] {
+			assert!(validate_field("board", "serial", bad).is_err(), "{}", bad);
+		}
+
+		let error = loaded("bad-serial", "boards:\n  a: {type: usb, serial: \"-l\", port: \"1\"}\n")
+			.unwrap_err()
+			.to_string();
+		assert!(error.contains("a: serial \"-l\" may only contain letters and digits"), "{}", error);
+	}
+
+	#[test]
+	fn sections_must_match_the_type()
+	{
+		let cases = [
+			("{type: usb, serial: YK1, port: \"1\", sim: {}}", "usb board has a sim section"),
+			("{type: qemu}", "qemu board has no qemu section"),
+			("{type: sim, serial: YK1, sim: {}}", "sim board has a serial or port set"),
+			("{type: command}", "command board has no command section"),
+			("{type: command, command: {on: [pdu, \"{outlet}\"], off: [pdu], status: [pdu]}}",
+			 "on command: no such placeholder as {outlet}"),
+			("{type: snmp}", "snmp board has no snmp section"),
+			("{type: snmp, snmp: {host: -c, outlet: 1, community: public}}",
+			 "snmp host \"-c\" is not a host name or address"),
+		];
+
+		for (i, (board, expected)) in cases.iter().enumerate() {
+			let yaml = format!("boards:\n  a: {}\n", board);
+			let error = loaded(&format!("sections-{}", i), &yaml).unwrap_err().to_string();
+			assert!(error.contains(expected), "{}: {}", board, error);
+		}
+	}
+
+	#[test]
+	fn check_flags_shared_ports_missing_serials_and_uarts()
+	{
+		let yaml = "\
+boards:
+  a: {type: usb, serial: YK1, port: \"1\"}
+  b: {type: usb, serial: YK1, port: \"1\"}
+  unmatched: {type: relay}
+  c: {type: usb, serial: YK1, port: \"2\", uart: {pattern: usb-NOWHERE, primary: if00-port0}}
+";
+		let problems = problems(&loaded("check", yaml).unwrap());
+
+		assert_eq!(problems, vec![
+			"b: hub YK1 port 1 is already used by a".to_string(),
+			"unmatched: relay board has no serial or port set".to_string(),
+			format!("c: no uart matching usb-NOWHERE under {}", UART_BY_ID),
+		]);
+	}
+
+	#[test]
+	fn check_flags_command_boards_switched_the_same_way()
+	{
+		let yaml = "\
+boards:
+  a: {type: command, command: {on: [pdu, on], off: [pdu, off], status: [pdu]}}
+  b: {type: command, command: {on: [pdu, on], off: [pdu, off], status: [pdu]}}
+  c: {type: command, command: {on: [pdu, on, \"{board}\"], off: [pdu, off, \"{board}\"], status: [pdu]}}
+  d: {type: command, serial: pdu2, port: \"1\", command: {on: [pdu, \"{serial}\", \"{port}\"], off: [pdu], status: [pdu]}}
+  e: {type: command, serial: pdu2, port: \"2\", command: {on: [pdu, \"{serial}\", \"{port}\"], off: [pdu, off], status: [pdu]}}
+";
+		let problems: Vec<String> = problems(&loaded("check-command", yaml).unwrap())
+			.into_iter()
+			.filter(|problem| return problem.contains("switched"))
+			.collect();
+
+		assert_eq!(problems, vec![
+			"b: switched by the same command as a".to_string(),
+			"e: switched by the same command as a".to_string(),
+		]);
+	}
+}
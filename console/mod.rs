@@ -0,0 +1,986 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use regex::bytes::Regex;
+use std::{
+	collections::VecDeque,
+	fmt,
+	io::{self, Read, Write},
+	os::unix::net::UnixStream,
+	path::{Path, PathBuf},
+	sync::{Arc, Condvar, Mutex, Weak, mpsc},
+	thread,
+	time::{Duration, Instant, SystemTime},
+};
+use log::debug;
+
+use crate::boards::{Board, Ops};
+use crate::jobs::Cancel;
+use crate::power;
+
+/* lines kept around for scrolling back through in the TUI */
+const SCROLLBACK: usize = 5000;
+/* raw output kept around for sessions that have fallen behind */
+const MAX_DATA: usize = 4 * 1024 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const BREAK_LENGTH: Duration = Duration::from_millis(250);
+/* how often a session waiting on the uart looks to see if it's been cancelled */
+const CANCEL_POLL: Duration = Duration::from_millis(200);
+/* uarts that are QEMU's serial port, as a unix socket, start with this */
+pub const SOCKET: &str = "unix:";
+
+#[derive(Debug)]
+pub struct ConsoleError {
+	details: String
+}
+
+impl ConsoleError {
+	pub fn new(msg: &str) -> ConsoleError {
+		return ConsoleError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for ConsoleError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Console failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for ConsoleError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+#[derive(Clone, Debug)]
+pub enum Line {
+	Output(String),
+	/// something lab itself wants to point out, like a boot stage matching
+	Marker(String),
+}
+
+/// Whatever a tap gets: what came in over the uart, byte for byte bar any
+/// secrets, or a note of lab's own
+#[derive(Clone, Debug)]
+pub enum Chunk {
+	Output(Vec<u8>),
+	Marker(String),
+}
+
+/// A chunk along with when it turned up, for anyone keeping a record
+#[derive(Clone, Debug)]
+pub struct Stamped {
+	pub at: SystemTime,
+	pub chunk: Chunk,
+}
+
+struct Buffer {
+	data: Vec<u8>,
+	/// how much has been dropped off the front of data
+	start: usize,
+	lines: VecDeque<Line>,
+	partial: String,
+	error: Option<String>,
+	/// never to be shown, should the board echo them back
+	secrets: Vec<String>,
+	/// everyone wanting everything as it comes in
+	taps: Vec<mpsc::Sender<Stamped>>,
+	/// the end of what came in last, held back from the taps while it could
+	/// yet turn out to be the start of a secret
+	held: Vec<u8>,
+}
+
+/// what gets written to, for whichever kind of uart it is
+enum Port {
+	Serial(Box<dyn serialport::SerialPort>),
+	/// QEMU's serial port, which comes & goes along with QEMU itself
+	Socket(UnixStream),
+}
+
+/// One uart, read by a single background thread and shared by everyone who
+/// wants to look at it, be that the TUI or a boot test.
+pub struct Console {
+	pub path: String,
+	buffer: Mutex<Buffer>,
+	arrived: Condvar,
+	/// nothing while a socket has nobody at the other end
+	writer: Mutex<Option<Port>>,
+}
+
+static CONSOLES: Mutex<Vec<(String, Weak<Console>)>> = Mutex::new(Vec::new());
+
+/// Get at the console on a uart, opening it if nobody else has already.
+/// It gets closed again once the last user lets go of it.
+pub fn attach(path: &str) -> Result<Arc<Console>, Box<dyn std::error::Error>>
+{
+	let mut consoles = CONSOLES.lock().unwrap();
+	consoles.retain(|(_, console)| return console.strong_count() > 0);
+
+	let existing = consoles
+		.iter()
+		.find(|(other, _)| return other == path)
+		.and_then(|(_, console)| return console.upgrade());
+	if let Some(console) = existing {
+		return Ok(console)
+	}
+
+	let console = Console::open(path)?;
+	consoles.push((path.to_string(), Arc::downgrade(&console)));
+
+	return Ok(console)
+}
+
+/// whether there is anything at the end of a uart right now
+pub fn present(path: &str) -> bool
+{
+	return Path::new(path.strip_prefix(SOCKET).unwrap_or(path)).exists()
+}
+
+fn open_port(path: &str) -> Result<Box<dyn serialport::SerialPort>, Box<dyn std::error::Error>>
+{
+	let mut attempts = 0;
+
+	if !Path::new(path).exists() {
+		return Err(Box::new(ConsoleError::new(&format!("{} does not exist", path))))
+	}
+
+	/*
+	 * The port is opened exclusively, so give the reader thread of a
+	 * console that was only just let go of a moment to notice and close it.
+	 */
+	loop {
+		match serialport::new(path, 115_200).timeout(READ_TIMEOUT).open() {
+			Ok(port) => return Ok(port),
+			Err(e) if attempts >= 5 => return Err(Box::new(e)),
+			Err(_) => attempts += 1,
+		}
+		thread::sleep(READ_TIMEOUT);
+	}
+}
+
+/// Drop anything that would upset a terminal, escape sequences included
+fn printable(text: &str) -> String
+{
+	let mut line = String::new();
+	let mut chars = text.chars();
+
+	while let Some(c) = chars.next() {
+		if c == '\u{1b}' {
+			/* skip "ESC [ ... letter" */
+			for c in chars.by_ref() {
+				if c.is_ascii_alphabetic() {
+					break;
+				}
+			}
+		} else if c == '\t' || !c.is_control() {
+			line.push(c);
+		}
+	}
+
+	return line
+}
+
+/// data with every from in it swapped for to
+fn replace_bytes(data: &[u8], from: &[u8], to: &[u8]) -> Vec<u8>
+{
+	let mut replaced = Vec::new();
+	let mut i = 0;
+
+	while i < data.len() {
+		if data[i..].starts_with(from) {
+			replaced.extend_from_slice(to);
+			i += from.len();
+		} else {
+			replaced.push(data[i]);
+			i += 1;
+		}
+	}
+
+	return replaced
+}
+
+impl Buffer {
+	fn redacted_bytes(&self, data: &[u8]) -> Vec<u8>
+	{
+		let mut data = data.to_vec();
+
+		for secret in self.secrets.iter() {
+			data = replace_bytes(&data, secret.as_bytes(), b"********");
+		}
+
+		return data
+	}
+
+	/// how much of the end of data could be the start of a secret
+	fn secret_prefix(&self, data: &[u8]) -> usize
+	{
+		return self.secrets
+			.iter()
+			.flat_map(|secret| {
+				return (1..secret.len())
+					.filter(|n| return data.ends_with(&secret.as_bytes()[..*n]))
+			})
+			.max()
+			.unwrap_or(0)
+	}
+
+	fn send_taps(&mut self, chunk: Chunk)
+	{
+		let stamped = Stamped {
+			at: SystemTime::now(),
+			chunk,
+		};
+		self.taps.retain(|tap| return tap.send(stamped.clone()).is_ok());
+	}
+
+	/// everything held back for the taps, secret or not, as nothing more
+	/// is coming that could make it one
+	fn release(&mut self)
+	{
+		if !self.held.is_empty() {
+			let held = std::mem::take(&mut self.held);
+			self.send_taps(Chunk::Output(held));
+		}
+	}
+
+	fn redacted(&self, text: &str) -> String
+	{
+		let mut text = text.to_string();
+
+		for secret in self.secrets.iter() {
+			text = text.replace(secret.as_str(), "********");
+		}
+
+		return text
+	}
+
+	fn scrub(&self, text: &str) -> String
+	{
+		return self.redacted(&printable(text))
+	}
+
+	fn push_line(&mut self, line: Line)
+	{
+		if self.lines.len() >= SCROLLBACK {
+			self.lines.pop_front();
+		}
+		self.lines.push_back(line);
+	}
+
+	fn append(&mut self, data: &[u8])
+	{
+		self.data.extend_from_slice(data);
+		if self.data.len() > MAX_DATA {
+			let drop = self.data.len() - MAX_DATA / 2;
+			self.data.drain(..drop);
+			self.start += drop;
+		}
+
+		if !self.taps.is_empty() {
+			let mut raw = std::mem::take(&mut self.held);
+			raw.extend_from_slice(data);
+			let mut raw = self.redacted_bytes(&raw);
+			self.held = raw.split_off(raw.len() - self.secret_prefix(&raw));
+			if !raw.is_empty() {
+				self.send_taps(Chunk::Output(raw));
+			}
+		}
+
+		let text = String::from_utf8_lossy(data);
+		for c in text.chars() {
+			if c == '\n' {
+				let line = self.scrub(&self.partial);
+				self.partial.clear();
+				self.push_line(Line::Output(line));
+			} else {
+				self.partial.push(c);
+			}
+		}
+	}
+}
+
+impl Console {
+	fn open(path: &str) -> Result<Arc<Console>, Box<dyn std::error::Error>>
+	{
+		let console = Arc::new(Console {
+			path: path.to_string(),
+			buffer: Mutex::new(Buffer {
+				data: Vec::new(),
+				start: 0,
+				lines: VecDeque::new(),
+				partial: String::new(),
+				error: None,
+				secrets: Vec::new(),
+				taps: Vec::new(),
+				held: Vec::new(),
+			}),
+			arrived: Condvar::new(),
+			writer: Mutex::new(None),
+		});
+		let weak = Arc::downgrade(&console);
+
+		/* a console to say what would be sent on, with nothing behind it */
+		if power::dry_run() && path != "n/a" {
+			println!("would open {}", path);
+			return Ok(console)
+		}
+
+		/* nothing might be listening yet, QEMU only is while powered on */
+		if let Some(socket) = path.strip_prefix(SOCKET) {
+			let socket = PathBuf::from(socket);
+			let stream = match connect(&socket) {
+				Ok((stream, writer)) => {
+					*console.writer.lock().unwrap() = Some(Port::Socket(writer));
+					Some(stream)
+				},
+				Err(_) => None,
+			};
+			thread::spawn(move || {
+				return follow(socket, stream, weak)
+			});
+
+			debug!("console following {}", path);
+			return Ok(console)
+		}
+
+		let mut port = open_port(path)?;
+		*console.writer.lock().unwrap() = Some(Port::Serial(port.try_clone()?));
+		thread::spawn(move || {
+			if let Err(e) = read_into(&mut port, &weak) {
+				if let Some(console) = weak.upgrade() {
+					console.buffer.lock().unwrap().error = Some(format!("{}: {}", console.path, e));
+					console.arrived.notify_all();
+				}
+			}
+		});
+
+		debug!("console attached to {}", path);
+		return Ok(console)
+	}
+
+	fn not_connected(&self) -> Box<dyn std::error::Error>
+	{
+		return Box::new(ConsoleError::new(&format!(
+			"{} is not connected, is the board powered on?", self.path)))
+	}
+
+	/// write to the uart as-is
+	pub fn send(&self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>>
+	{
+		if power::dry_run() {
+			let text = self.buffer.lock().unwrap().redacted(&String::from_utf8_lossy(data));
+			println!("would send {:?} to {}", text, self.path);
+			return Ok(())
+		}
+
+		let mut writer = self.writer.lock().unwrap();
+		let writer: &mut dyn Write = match writer.as_mut() {
+			Some(Port::Serial(port)) => port,
+			Some(Port::Socket(socket)) => socket,
+			None => return Err(self.not_connected()),
+		};
+		writer.write_all(data)?;
+		writer.flush()?;
+
+		return Ok(())
+	}
+
+	/// add a note of lab's own in amongst the output
+	pub fn mark(&self, text: &str)
+	{
+		let mut buffer = self.buffer.lock().unwrap();
+
+		buffer.send_taps(Chunk::Marker(text.to_string()));
+		buffer.push_line(Line::Marker(text.to_string()));
+	}
+
+	/// keep something out of everything shown from here on
+	pub fn redact(&self, secret: &str)
+	{
+		let mut buffer = self.buffer.lock().unwrap();
+
+		if !secret.is_empty() && !buffer.secrets.iter().any(|known| return known == secret) {
+			buffer.secrets.push(secret.to_string());
+		}
+	}
+
+	/// everything from here on, output as it came in & markers alike, until
+	/// the receiver is dropped
+	pub fn tap(&self) -> mpsc::Receiver<Stamped>
+	{
+		let (tx, rx) = mpsc::channel();
+		self.buffer.lock().unwrap().taps.push(tx);
+
+		return rx
+	}
+
+	/// hand the taps whatever was being held back in case it was the start
+	/// of a secret, for when they are about to stop listening
+	pub fn release(&self)
+	{
+		self.buffer.lock().unwrap().release();
+	}
+
+	pub fn send_break(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		if power::dry_run() {
+			println!("would send a break to {}", self.path);
+			return Ok(())
+		}
+
+		let writer = self.writer.lock().unwrap();
+		let port = match writer.as_ref() {
+			Some(Port::Serial(port)) => port,
+			Some(Port::Socket(_)) => return Err(Box::new(ConsoleError::new(&format!(
+				"{} can't send a break", self.path)))),
+			None => return Err(self.not_connected()),
+		};
+		port.set_break()?;
+		thread::sleep(BREAK_LENGTH);
+		port.clear_break()?;
+
+		return Ok(())
+	}
+
+	/// the lines that fit in height, scroll lines back from the end
+	pub fn window(&self, height: usize, scroll: usize) -> Vec<Line>
+	{
+		let buffer = self.buffer.lock().unwrap();
+		let mut lines: Vec<Line> = buffer.lines.iter().cloned().collect();
+
+		if !buffer.partial.is_empty() {
+			lines.push(Line::Output(buffer.scrub(&buffer.partial)));
+		}
+		if let Some(error) = &buffer.error {
+			lines.push(Line::Marker(error.clone()));
+		}
+
+		let end = lines.len().saturating_sub(scroll);
+		let start = end.saturating_sub(height);
+		return lines[start..end].to_vec()
+	}
+
+	/// start reading from whatever arrives from here on
+	pub fn session(self: &Arc<Self>, timeout: Duration) -> Session
+	{
+		let buffer = self.buffer.lock().unwrap();
+
+		return Session {
+			console: self.clone(),
+			offset: buffer.start + buffer.data.len(),
+			timeout,
+			cancel: None,
+		}
+	}
+}
+
+/// Read until the console is let go of, which is Ok, or the port fails
+fn read_into(port: &mut dyn Read, console: &Weak<Console>) -> io::Result<()>
+{
+	let mut data = [0u8; 1024];
+
+	loop {
+		let result = port.read(&mut data);
+
+		let console = match console.upgrade() {
+			Some(console) => console,
+			None => return Ok(()),
+		};
+
+		match result {
+			Ok(0) => continue,
+			Ok(read) => console.buffer.lock().unwrap().append(&data[..read]),
+			Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+			Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+			Err(e) => return Err(e),
+		}
+
+		console.arrived.notify_all();
+	}
+}
+
+/// a socket read of nothing is the other end going away, not a timeout
+struct Hangup(UnixStream);
+
+impl Read for Hangup {
+	fn read(&mut self, data: &mut [u8]) -> io::Result<usize>
+	{
+		match self.0.read(data)? {
+			0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "disconnected")),
+			read => return Ok(read),
+		}
+	}
+}
+
+/// a socket to read from, and the same again to write to
+fn connect(socket: &Path) -> io::Result<(UnixStream, UnixStream)>
+{
+	let stream = UnixStream::connect(socket)?;
+	stream.set_read_timeout(Some(READ_TIMEOUT))?;
+	let writer = stream.try_clone()?;
+
+	return Ok((stream, writer))
+}
+
+/// Keep reading a socket through whatever is at the other end going away &
+/// coming back, as QEMU does each time a virtual board is power cycled
+fn follow(socket: PathBuf, mut connected: Option<UnixStream>, console: Weak<Console>)
+{
+	loop {
+		let stream = match connected.take() {
+			Some(stream) => stream,
+			None => match connect(&socket) {
+				Ok((stream, writer)) => {
+					match console.upgrade() {
+						Some(console) => *console.writer.lock().unwrap() = Some(Port::Socket(writer)),
+						None => return,
+					}
+					stream
+				},
+				Err(_) if console.strong_count() > 0 => {
+					thread::sleep(READ_TIMEOUT);
+					continue;
+				},
+				Err(_) => return,
+			},
+		};
+		debug!("connected to {}", socket.display());
+
+		let result = read_into(&mut Hangup(stream), &console);
+
+		match console.upgrade() {
+			Some(console) => *console.writer.lock().unwrap() = None,
+			None => return,
+		}
+		if let Err(e) = result {
+			debug!("{}: {}", socket.display(), e);
+		}
+	}
+}
+
+/// which regex matched, what came before it and what it matched
+pub type Found = (usize, String, String);
+
+/// A cursor into a console's output, for waiting on things to show up.
+pub struct Session {
+	console: Arc<Console>,
+	offset: usize,
+	pub timeout: Duration,
+	cancel: Option<Cancel>,
+}
+
+impl Session {
+	/// give up waiting, with a CancelledError, once cancel is
+	pub fn watch(&mut self, cancel: &Cancel)
+	{
+		self.cancel = Some(cancel.clone());
+	}
+
+	/// Wait for whichever of several regexes matches first in anything
+	/// received since the last match, handing back which one it was as well
+	/// as what came before it and the match, secrets blanked out. Nothing
+	/// matching by the deadline is for the caller to decide about.
+	pub fn find(&mut self, regexes: &[Regex], deadline: Instant)
+	-> Result<Option<Found>, Box<dyn std::error::Error>>
+	{
+		let mut buffer = self.console.buffer.lock().unwrap();
+
+		loop {
+			/* fallen so far behind that output was thrown away */
+			if self.offset < buffer.start {
+				self.offset = buffer.start;
+			}
+
+			let unread = &buffer.data[self.offset - buffer.start..];
+			let first = regexes
+				.iter()
+				.enumerate()
+				.filter_map(|(i, regex)| return Some((i, regex.find(unread)?)))
+				.min_by_key(|(_, found)| return found.start());
+
+			if let Some((i, found)) = first {
+				let before = buffer.redacted(&String::from_utf8_lossy(&unread[..found.start()]));
+				let matched = buffer.redacted(&String::from_utf8_lossy(found.as_bytes()));
+				self.offset += found.end();
+				return Ok(Some((i, before, matched)))
+			}
+
+			if let Some(error) = &buffer.error {
+				return Err(Box::new(ConsoleError::new(error)))
+			}
+
+			if let Some(cancel) = &self.cancel {
+				cancel.check()?;
+			}
+
+			let now = Instant::now();
+			if now >= deadline {
+				return Ok(None)
+			}
+
+			let wait = match self.cancel {
+				Some(_) => (deadline - now).min(CANCEL_POLL),
+				None => deadline - now,
+			};
+			buffer = self.console.arrived.wait_timeout(buffer, wait).unwrap().0;
+		}
+	}
+
+	/// whatever has arrived since last time, waiting up to timeout for it
+	pub fn read(&mut self, timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+	{
+		let mut buffer = self.console.buffer.lock().unwrap();
+
+		if self.offset < buffer.start {
+			self.offset = buffer.start;
+		}
+		if self.offset == buffer.start + buffer.data.len() {
+			if let Some(error) = &buffer.error {
+				return Err(Box::new(ConsoleError::new(error)))
+			}
+			buffer = self.console.arrived.wait_timeout(buffer, timeout).unwrap().0;
+		}
+
+		let data = buffer.data[self.offset - buffer.start..].to_vec();
+		self.offset += data.len();
+
+		return Ok(data)
+	}
+
+	/// everything that has arrived and not been matched yet, secrets blanked
+	pub fn drain(&mut self) -> String
+	{
+		let buffer = self.console.buffer.lock().unwrap();
+
+		if self.offset < buffer.start {
+			self.offset = buffer.start;
+		}
+
+		let unread = &buffer.data[self.offset - buffer.start..];
+		self.offset += unread.len();
+
+		return buffer.redacted(&String::from_utf8_lossy(unread))
+	}
+
+	pub fn send(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.console.send(data)
+	}
+
+	pub fn send_break(&mut self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.console.send_break()
+	}
+
+	pub fn send_line(&mut self, line: &str) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.console.send(format!("{}\n", line).as_bytes())
+	}
+
+	pub fn mark(&self, text: &str)
+	{
+		self.console.mark(text);
+	}
+
+	pub fn redact(&self, secret: &str)
+	{
+		self.console.redact(secret);
+	}
+}
+
+/* Ctrl-], as in telnet */
+const ESCAPE: u8 = 0x1d;
+
+/// puts the terminal back how it was found, however terminal() returns
+struct RawMode;
+
+impl RawMode {
+	fn enable() -> Result<RawMode, Box<dyn std::error::Error>>
+	{
+		enable_raw_mode()?;
+		return Ok(RawMode)
+	}
+}
+
+impl Drop for RawMode {
+	fn drop(&mut self)
+	{
+		let _ = disable_raw_mode();
+	}
+}
+
+fn say(stdout: &mut io::Stdout, text: &str) -> Result<(), Box<dyn std::error::Error>>
+{
+	write!(stdout, "\r\n[lab: {}]\r\n", text)?;
+	stdout.flush()?;
+
+	return Ok(())
+}
+
+/// Hook the terminal up to a board's uart, with Ctrl-] followed by q, b or r
+/// to quit, send a break or power cycle the board. With reset, the board is
+/// power cycled once attached so that nothing it prints is missed.
+pub fn terminal(board: &Board, reset: bool) -> Result<(), Box<dyn std::error::Error>>
+{
+	if board.primary_uart == "n/a" {
+		return Err(Box::new(ConsoleError::new(&format!(
+			"{} has no uart configured", board.name))))
+	}
+
+	let console = attach(&board.primary_uart)?;
+	let mut session = console.session(Duration::ZERO);
+	let mut stdout = io::stdout();
+
+	if power::dry_run() {
+		println!("would hook the terminal up to {}", console.path);
+		if reset {
+			board.reboot()?;
+		}
+		return Ok(())
+	}
+
+	if reset {
+		board.reboot()?;
+	}
+
+	let (keys, pressed) = mpsc::channel();
+	thread::spawn(move || {
+		let mut stdin = io::stdin();
+		let mut key = [0u8; 1];
+
+		while let Ok(1) = stdin.read(&mut key) {
+			if keys.send(key[0]).is_err() {
+				return;
+			}
+		}
+	});
+
+	let _raw_mode = RawMode::enable()?;
+	say(&mut stdout, &format!("attached to {}, Ctrl-] ? for help", console.path))?;
+
+	let mut escaped = false;
+	loop {
+		stdout.write_all(&session.read(READ_TIMEOUT)?)?;
+		stdout.flush()?;
+
+		while let Ok(key) = pressed.try_recv() {
+			if !escaped {
+				if key == ESCAPE {
+					escaped = true;
+				} else {
+					session.send(&[key])?;
+				}
+				continue;
+			}
+
+			escaped = false;
+			match key {
+				b'q' | b'.' => {
+					say(&mut stdout, "detached")?;
+					return Ok(())
+				},
+				b'b' => {
+					session.send_break()?;
+					say(&mut stdout, "sent break")?;
+				},
+				b'r' => {
+					say(&mut stdout, "power cycling")?;
+					if let Err(e) = board.reboot() {
+						say(&mut stdout, &e.to_string())?;
+					}
+				},
+				ESCAPE => session.send(&[ESCAPE])?,
+				_ => say(&mut stdout, "Ctrl-] then q: quit, b: send break, r: power cycle, Ctrl-]: send Ctrl-]")?,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::boards::{Expect, Outcome};
+
+	fn buffer() -> Buffer
+	{
+		return Buffer {
+			data: Vec::new(),
+			start: 0,
+			lines: VecDeque::new(),
+			partial: String::new(),
+			error: None,
+			secrets: Vec::new(),
+			taps: Vec::new(),
+			held: Vec::new(),
+		}
+	}
+
+	/// everything a tap has been sent so far, output run together
+	fn tapped(tap: &mpsc::Receiver<Stamped>) -> Vec<u8>
+	{
+		let mut output = Vec::new();
+
+		for stamped in tap.try_iter() {
+			match stamped.chunk {
+				Chunk::Output(data) => output.extend_from_slice(&data),
+				Chunk::Marker(text) => output.extend_from_slice(format!("[{}]", text).as_bytes()),
+			}
+		}
+
+		return output
+	}
+
+	#[test]
+	fn taps_get_output_byte_for_byte()
+	{
+		let mut buffer = buffer();
+		let (tx, tap) = mpsc::channel();
+		buffer.taps.push(tx);
+
+		buffer.append(b"U-Boot\r\n\x1b[1m=> \x07");
+
+		assert_eq!(tapped(&tap), b"U-Boot\r\n\x1b[1m=> \x07");
+		assert!(matches!(buffer.lines.back(), Some(Line::Output(line)) if line == "U-Boot"));
+	}
+
+	#[test]
+	fn secrets_split_across_reads_are_still_redacted()
+	{
+		let mut buffer = buffer();
+		let (tx, tap) = mpsc::channel();
+		buffer.taps.push(tx);
+		buffer.secrets.push("hunter2".to_string());
+
+		buffer.append(b"Password: hun");
+		assert_eq!(tapped(&tap), b"Password: ");
+		buffer.append(b"ter2\r\n# h");
+		assert_eq!(tapped(&tap), b"********\r\n# ");
+
+		/* nothing more is coming, so that h was never a secret */
+		buffer.release();
+		assert_eq!(tapped(&tap), b"h");
+	}
+
+	/// a console with nothing behind it, for tests to say what the board printed
+	fn console() -> Arc<Console>
+	{
+		return Arc::new(Console {
+			path: "test".to_string(),
+			buffer: Mutex::new(buffer()),
+			arrived: Condvar::new(),
+			writer: Mutex::new(None),
+		})
+	}
+
+	fn feed(console: &Console, data: &str)
+	{
+		console.buffer.lock().unwrap().append(data.as_bytes());
+		console.arrived.notify_all();
+	}
+
+	/// the board prints each of these after its delay, counted from when the last one was
+	fn later(console: &Arc<Console>, output: &[(u64, &'static str)])
+	{
+		let console = console.clone();
+		let output = output.to_vec();
+
+		thread::spawn(move || {
+			for (delay, data) in output {
+				thread::sleep(Duration::from_millis(delay));
+				feed(&console, data);
+			}
+		});
+	}
+
+	fn waiting(success: &[&str], failure: &[&str], retry: &[&str], timeout: Duration) -> Expect
+	{
+		let strings = |patterns: &[&str]| {
+			return patterns.iter().map(|pattern| return pattern.to_string()).collect()
+		};
+
+		return Expect {
+			success: strings(success),
+			failure: strings(failure),
+			retry: strings(retry),
+			timeout,
+		}
+	}
+
+	#[test]
+	fn whatever_turns_up_first_matches()
+	{
+		let console = console();
+		let mut session = console.session(Duration::from_secs(5));
+		feed(&console, "U-Boot 2023.10\r\nLinux version 6.6.0\r\n");
+
+		let expect = waiting(&["Linux version", "U-Boot"], &[], &[], Duration::from_secs(5));
+		let matched = Board::default().expect(&mut session, &expect).unwrap();
+		assert_eq!(matched.outcome, Outcome::Success);
+		assert_eq!(matched.pattern, "U-Boot");
+
+		/* and where two match at the same place, the first given */
+		let expect = waiting(&["Linux", "Linux version"], &[], &[], Duration::from_secs(5));
+		let matched = Board::default().expect(&mut session, &expect).unwrap();
+		assert_eq!(matched.pattern, "Linux");
+		assert_eq!(matched.text, "Linux");
+	}
+
+	#[test]
+	fn failures_give_up_without_waiting_out_the_timeout()
+	{
+		let console = console();
+		let mut session = console.session(Duration::from_secs(60));
+		later(&console, &[(100, "Run /sbin/init\r\n[    1.2] Kernel panic - not syncing: init\r\n")]);
+
+		let started = Instant::now();
+		let expect = waiting(&["login: "], &["Kernel panic[^\r\n]*"], &[], Duration::from_secs(60));
+		let matched = Board::default().expect(&mut session, &expect).unwrap();
+
+		assert_eq!(matched.outcome, Outcome::Failure);
+		assert_eq!(matched.text, "Kernel panic - not syncing: init");
+		assert!(started.elapsed() < Duration::from_secs(5));
+	}
+
+	#[test]
+	fn retries_start_the_wait_over()
+	{
+		let console = console();
+		let mut session = console.session(Duration::from_secs(5));
+		later(&console, &[
+			(600, "Hit any key to stop autoboot:  2\r\n"),
+			(600, "Hit any key to stop autoboot:  1\r\n"),
+			(600, "Linux version 6.6.0\r\n"),
+		]);
+
+		let started = Instant::now();
+		let expect = waiting(&["Linux version"], &[], &["Hit any key"], Duration::from_secs(1));
+		let matched = Board::default().expect(&mut session, &expect).unwrap();
+
+		assert_eq!(matched.outcome, Outcome::Success);
+		assert!(started.elapsed() > Duration::from_millis(1500));
+		assert!(console.window(10, 0).iter().any(|line| {
+			return matches!(line, Line::Marker(text) if text == "Still going: Hit any key")
+		}));
+	}
+
+	#[test]
+	fn transcripts_have_everything_up_to_the_match()
+	{
+		let console = console();
+		let mut session = console.session(Duration::from_secs(5));
+		feed(&console, "OpenSBI\r\nHit any key\r\nLinux version 6.6.0\r\nRun /sbin/init");
+
+		let expect = waiting(&["Linux version"], &[], &["Hit any key"], Duration::from_secs(5));
+		let matched = Board::default().expect(&mut session, &expect).unwrap();
+		assert_eq!(matched.transcript, "OpenSBI\r\nHit any key\r\nLinux version");
+
+		/* and on a timeout, whatever there was */
+		let expect = waiting(&["login: "], &[], &[], Duration::from_millis(200));
+		let matched = Board::default().expect(&mut session, &expect).unwrap();
+		assert_eq!(matched.outcome, Outcome::Timeout);
+		assert_eq!(matched.transcript, " 6.6.0\r\nRun /sbin/init");
+	}
+}
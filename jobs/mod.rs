@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{
+	fmt,
+	sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+	thread,
+	time::{Duration, Instant},
+};
+use log::debug;
+
+use crate::boards::Board;
+
+#[derive(Debug)]
+pub struct CancelledError {
+	details: String
+}
+
+impl CancelledError {
+	pub fn new(msg: &str) -> CancelledError {
+		return CancelledError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for CancelledError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Cancelled: {}", self.details)
+	}
+}
+
+impl std::error::Error for CancelledError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+/// Handed to every job so that long running ones can notice they have been
+/// cancelled in between steps.
+#[derive(Clone, Debug, Default)]
+pub struct Cancel {
+	cancelled: Arc<AtomicBool>,
+}
+
+impl Cancel {
+	pub fn cancel(&self)
+	{
+		self.cancelled.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_cancelled(&self) -> bool
+	{
+		return self.cancelled.load(Ordering::SeqCst)
+	}
+
+	/// bail out with an error if the job has been cancelled
+	pub fn check(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		if self.is_cancelled() {
+			return Err(Box::new(CancelledError::new("job cancelled")))
+		}
+
+		return Ok(())
+	}
+}
+
+pub type Action = fn(&Board, &Cancel) -> Result<(), Box<dyn std::error::Error>>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobState {
+	Running,
+	/// asked to stop, but still has hold of the board until it notices
+	Cancelling,
+	Succeeded,
+	Failed(String),
+	Cancelled,
+}
+
+#[derive(Clone, Debug)]
+pub struct Job {
+	pub id: usize,
+	pub board: String,
+	pub action: String,
+	pub state: JobState,
+	pub started: Instant,
+	pub finished: Option<Instant>,
+	cancel: Cancel,
+}
+
+impl Job {
+	/// whether the job's thread could still be touching the board
+	pub fn busy(&self) -> bool
+	{
+		return matches!(self.state, JobState::Running | JobState::Cancelling)
+	}
+
+	pub fn duration(&self) -> Duration
+	{
+		match self.finished {
+			Some(finished) => return finished - self.started,
+			None => return self.started.elapsed(),
+		}
+	}
+}
+
+/// Runs actions on boards in the background, keeping track of how they got
+/// on so the TUI can show them without ever waiting on one.
+#[derive(Default)]
+pub struct Jobs {
+	jobs: Arc<Mutex<Vec<Job>>>,
+	next_id: usize,
+}
+
+impl Jobs {
+	pub fn spawn(&mut self, board: &Board, name: &str, action: Action)
+	{
+		let mut jobs = self.jobs.lock().unwrap();
+		let id = self.next_id;
+		self.next_id += 1;
+
+		let mut job = Job {
+			id,
+			board: board.name.clone(),
+			action: name.to_string(),
+			state: JobState::Running,
+			started: Instant::now(),
+			finished: None,
+			cancel: Cancel::default(),
+		};
+
+		/* two things fighting over the one power switch or uart never ends well */
+		if jobs.iter().any(|other| return other.board == board.name && other.busy()) {
+			job.state = JobState::Failed(format!("{} is busy", board.name));
+			job.finished = Some(job.started);
+			jobs.push(job);
+			return;
+		}
+
+		let cancel = job.cancel.clone();
+		jobs.push(job);
+
+		let shared = self.jobs.clone();
+		let board = board.clone();
+		thread::spawn(move || {
+			debug!("job {}: starting on {}", id, board.name);
+			let state = match action(&board, &cancel) {
+				Ok(()) => JobState::Succeeded,
+				Err(e) => JobState::Failed(e.to_string()),
+			};
+			debug!("job {}: {:?}", id, state);
+
+			let mut jobs = shared.lock().unwrap();
+			if let Some(job) = jobs.iter_mut().find(|job| return job.id == id) {
+				/* a cancelled job stays cancelled however it ended up */
+				job.state = match job.state {
+					JobState::Cancelling => JobState::Cancelled,
+					_ => state,
+				};
+				job.finished = Some(Instant::now());
+			}
+		});
+	}
+
+	/// Cancel everything still running on a board. Jobs only finish being
+	/// cancelled once they notice, and keep the board busy until then.
+	pub fn cancel(&self, board_name: &str)
+	{
+		let mut jobs = self.jobs.lock().unwrap();
+
+		for job in jobs.iter_mut() {
+			if job.board == board_name && job.state == JobState::Running {
+				job.cancel.cancel();
+				job.state = JobState::Cancelling;
+			}
+		}
+	}
+
+	pub fn snapshot(&self) -> Vec<Job>
+	{
+		return self.jobs.lock().unwrap().clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/* holds on to the board until cancelled, like a boot test waiting on a uart */
+	fn wait_for_cancel(_board: &Board, cancel: &Cancel) -> Result<(), Box<dyn std::error::Error>>
+	{
+		while !cancel.is_cancelled() {
+			thread::sleep(Duration::from_millis(10));
+		}
+		thread::sleep(Duration::from_millis(200));
+
+		return cancel.check()
+	}
+
+	fn nothing(_board: &Board, _cancel: &Cancel) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return Ok(())
+	}
+
+	fn state_of(jobs: &Jobs, id: usize) -> JobState
+	{
+		return jobs.snapshot()[id].state.clone()
+	}
+
+	fn settle(jobs: &Jobs, id: usize)
+	{
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while jobs.snapshot()[id].busy() && Instant::now() < deadline {
+			thread::sleep(Duration::from_millis(10));
+		}
+	}
+
+	#[test]
+	fn cancelled_job_keeps_board_busy_until_it_stops()
+	{
+		let mut jobs = Jobs::default();
+		let board = Board::default();
+
+		jobs.spawn(&board, "wait", wait_for_cancel);
+		jobs.cancel(&board.name);
+		assert_eq!(state_of(&jobs, 0), JobState::Cancelling);
+		assert!(jobs.snapshot()[0].finished.is_none());
+
+		jobs.spawn(&board, "again", nothing);
+		assert_eq!(state_of(&jobs, 1), JobState::Failed(format!("{} is busy", board.name)));
+
+		settle(&jobs, 0);
+		assert_eq!(state_of(&jobs, 0), JobState::Cancelled);
+		assert!(jobs.snapshot()[0].finished.is_some());
+
+		jobs.spawn(&board, "after", nothing);
+		settle(&jobs, 2);
+		assert_eq!(state_of(&jobs, 2), JobState::Succeeded);
+	}
+
+	#[test]
+	fn jobs_on_other_boards_are_not_held_up()
+	{
+		let mut jobs = Jobs::default();
+		let waiting = Board::default();
+		let other = Board {
+			name: "other".to_string(),
+			..Board::default()
+		};
+
+		jobs.spawn(&waiting, "wait", wait_for_cancel);
+		jobs.spawn(&other, "nothing", nothing);
+		settle(&jobs, 1);
+		assert_eq!(state_of(&jobs, 1), JobState::Succeeded);
+
+		jobs.cancel(&waiting.name);
+		settle(&jobs, 0);
+		assert_eq!(state_of(&jobs, 0), JobState::Cancelled);
+	}
+}
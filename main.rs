@@ -3,45 +3,179 @@
 #![deny(clippy::implicit_return)]
 #![allow(clippy::needless_return)]
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::process;
 
 /// lab
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
 	/// input yaml config file
-	#[clap(short, long, default_value = "config.yaml")]
+	#[clap(short, long, global = true, default_value = "config.yaml")]
 	config: String,
 
-	/// board to operate on
-	#[clap(short, long, default_value = "icicle")]
-	board: String,
-	
-	/// command (reset, off, goodnight)
-	#[clap(short, long, default_value = "interactive")]
-	function: String,
+	/// print the power & uart operations that would happen, doing none of them
+	#[clap(long, global = true)]
+	dry_run: bool,
+
+	/// what to do, running the TUI if left out
+	#[clap(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// power boards on
+	On {
+		#[clap(required = true)]
+		boards: Vec<String>,
+	},
+	/// power boards off
+	Off {
+		#[clap(required = true)]
+		boards: Vec<String>,
+	},
+	/// power cycle boards
+	Reset {
+		#[clap(required = true)]
+		boards: Vec<String>,
+	},
+	/// show power & uart state of boards, all of them if none are given
+	Status {
+		boards: Vec<String>,
+
+		/// print json rather than a table
+		#[clap(long)]
+		json: bool,
+	},
+	/// show boards from the config & whether their switch is attached
+	List {
+		boards: Vec<String>,
+	},
+	/// power off boards, all of them if none are given
+	Goodnight {
+		boards: Vec<String>,
+	},
+	/// power cycle boards, all at once, and run through their boot test steps
+	BootTest {
+		#[clap(required_unless_present_any = &["all", "group"])]
+		boards: Vec<String>,
+
+		#[clap(flatten)]
+		options: boottest::Options,
+	},
+	/// attach to a board's uart, Ctrl-] ? for help once attached
+	Console {
+		board: String,
+
+		/// power cycle the board once attached
+		#[clap(long)]
+		reset: bool,
+	},
+	/// be simulated boards, all of them if none are given, until interrupted
+	Sim {
+		boards: Vec<String>,
+	},
+	/// control boards interactively, all of them if none are given
+	Tui {
+		boards: Vec<String>,
+	},
+	/// work with the config file itself
+	Config {
+		#[clap(subcommand)]
+		action: ConfigCommand,
+	},
 }
 
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+	/// parse the config and look for likely mistakes
+	Check,
+}
+
+mod config;
 mod ykcmd;
+mod ykhid;
+mod power;
+mod qemu;
+mod sim;
+mod poller;
+mod jobs;
+mod mock;
+mod command;
+mod snmp;
 mod boards;
+mod console;
+mod transcript;
+mod boottest;
+mod results;
 mod ui;
 
-fn main() -> Result<(),Box<dyn std::error::Error>> {
+/* 2 is left to clap for usage errors */
+const EXIT_FAILURE: i32 = 1;
+const EXIT_BAD_CONFIG: i32 = 3;
+const EXIT_NO_SUCH_BOARD: i32 = 4;
+const EXIT_NO_SUCH_HUB: i32 = 5;
+const EXIT_TOOL_FAILED: i32 = 6;
+const EXIT_BOOT_TEST_FAILED: i32 = 7;
+
+fn exit_code(err: &(dyn std::error::Error + 'static)) -> i32
+{
+	if err.is::<boards::BoardNotFoundError>() {
+		return EXIT_NO_SUCH_BOARD
+	}
+	if err.is::<boards::ConfigParsingError>() {
+		return EXIT_BAD_CONFIG
+	}
+	if err.is::<power::HubNotFoundError>() {
+		return EXIT_NO_SUCH_HUB
+	}
+	if err.is::<ykcmd::YkmdError>() || err.is::<ykhid::HidError>() || err.is::<qemu::QemuError>()
+	   || err.is::<sim::SimError>() || err.is::<mock::MockError>()
+	   || err.is::<command::CommandError>() || err.is::<snmp::SnmpError>() {
+		return EXIT_TOOL_FAILED
+	}
+	if err.is::<boottest::BootTestError>() {
+		return EXIT_BOOT_TEST_FAILED
+	}
+
+	return EXIT_FAILURE
+}
+
+fn run(command: Command, input_file: String) -> Result<(), Box<dyn std::error::Error>>
+{
+	match command {
+		Command::On { boards } => return ykcmd::power_on_boards(&boards, input_file),
+		Command::Off { boards } => return ykcmd::power_off_boards(&boards, input_file),
+		Command::Reset { boards } => return ykcmd::reboot_boards(&boards, input_file),
+		Command::Status { boards, json } => return ykcmd::status(&boards, input_file, json),
+		Command::List { boards } => return ykcmd::list_boards(&boards, input_file),
+		Command::Goodnight { boards } => return ykcmd::goodnight(&boards, input_file),
+		Command::BootTest { boards, options } => {
+			return boottest::run_boards(&boards, input_file, &options)
+		},
+		Command::Console { board, reset } => {
+			let boards = boards::get_boards_from_config(&[board], input_file)?;
+			return console::terminal(&boards[0], reset)
+		},
+		Command::Sim { boards } => return sim::serve(&boards, input_file),
+		Command::Tui { boards } => return ui::run_interactively(&boards, input_file),
+		Command::Config { action: ConfigCommand::Check } => return config::check(input_file),
+	}
+}
+
+fn main() {
 	let args = Args::parse();
-	let input_file = args.config;
-	let board = args.board;
+	let command = args.command.unwrap_or(Command::Tui { boards: Vec::new() });
 	stderrlog::new()
 		.module(module_path!())
 		.init()
 		.unwrap();
 
-	match args.function.as_str() {
-		"off" => return ykcmd::power_off_board(board, input_file),
-		"on" => return ykcmd::power_on_board(board, input_file),
-		"reset" => return ykcmd::reboot_board(board, input_file),
-		"goodnight" => return ykcmd::goodnight(input_file),
-		"interactive" => return ui::run_interactively(input_file),
-		_ => return Err(Box::new(ykcmd::YkmdError::new("Invalid function"))),
+	power::set_dry_run(args.dry_run);
+
+	if let Err(err) = run(command, args.config) {
+		eprintln!("{}", err);
+		process::exit(exit_code(&*err));
 	}
 }
-
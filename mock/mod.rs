@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{
+	collections::BTreeMap,
+	fmt, fs,
+	io::{Read, Seek, Write},
+	os::unix::io::AsRawFd,
+	path::{self, Path, PathBuf},
+};
+use log::debug;
+use crate::power::{Action, HubNotFoundError, PowerBackend};
+
+/*
+ * Pretend hubs, for trying lab out without any Yepkit hardware. The state of
+ * every port lives in the one yaml file, serial to port to whether it is on:
+ *   YK12345:
+ *     "1": true
+ * Any serial will do and a hub is only "attached" once it is in the file,
+ * which it is from the first time one of its ports gets switched.
+ */
+type Ports = BTreeMap<String, BTreeMap<String, bool>>;
+
+#[derive(Debug)]
+pub struct MockError {
+	details: String
+}
+
+impl MockError {
+	pub fn new(msg: &str) -> MockError {
+		return MockError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for MockError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "mock failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for MockError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+/// A port on a pretend hub
+#[derive(Debug)]
+pub struct Mock {
+	serial_number: String,
+	port_number: String,
+	state: PathBuf,
+}
+
+impl Mock {
+	pub fn new(serial_number: &str, port_number: &str, state: &Path) -> Mock {
+		return Mock {
+			serial_number: serial_number.to_string(),
+			port_number: port_number.to_string(),
+			state: path::absolute(state).unwrap_or_else(|_| return state.to_path_buf()),
+		}
+	}
+
+	fn error(&self, what: &str) -> Box<dyn std::error::Error>
+	{
+		return Box::new(MockError::new(&format!("{}: {}", self.state.display(), what)))
+	}
+
+	/// Look at, and maybe change, every port there is. The file is locked
+	/// throughout, so that boards switched side by side don't lose updates.
+	fn with_ports<T>(&self, change: impl FnOnce(&mut Ports) -> T)
+	-> Result<T, Box<dyn std::error::Error>>
+	{
+		if let Some(dir) = self.state.parent() {
+			fs::create_dir_all(dir).map_err(|e| return self.error(&e.to_string()))?;
+		}
+
+		let mut file = fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(&self.state)
+			.map_err(|e| return self.error(&e.to_string()))?;
+		/* let go of again when the file gets closed */
+		if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+			return Err(self.error(&std::io::Error::last_os_error().to_string()))
+		}
+
+		let mut contents = String::new();
+		file.read_to_string(&mut contents)?;
+		let mut ports: Ports = match contents.trim().is_empty() {
+			true => Ports::new(),
+			false => serde_yaml::from_str(&contents).map_err(|e| return self.error(&e.to_string()))?,
+		};
+		let before = ports.clone();
+
+		let result = change(&mut ports);
+
+		if ports != before {
+			file.set_len(0)?;
+			file.rewind()?;
+			file.write_all(serde_yaml::to_string(&ports)?.as_bytes())?;
+		}
+
+		return Ok(result)
+	}
+
+	fn set(&self, on: bool) -> Result<(), Box<dyn std::error::Error>>
+	{
+		self.with_ports(|ports| {
+			ports
+				.entry(self.serial_number.clone())
+				.or_default()
+				.insert(self.port_number.clone(), on);
+		})?;
+		debug!("{}@{} (mock) powered {}", self.serial_number, self.port_number, on);
+
+		return Ok(())
+	}
+}
+
+impl PowerBackend for Mock {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.set(true)
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.set(false)
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		return Ok(self.port_states(std::slice::from_ref(&self.port_number))?[0])
+	}
+
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		return self.with_ports(|ports| return ports.keys().cloned().collect())
+	}
+
+	fn hub(&self) -> String
+	{
+		return format!("mock:{}", self.serial_number)
+	}
+
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		let hub = self.with_ports(|all| return all.get(&self.serial_number).cloned())?
+			.ok_or_else(|| return HubNotFoundError::new(&self.serial_number))?;
+
+		/* a port never switched is off, as a hub's are when plugged in */
+		return Ok(ports.iter().map(|port| return hub.get(port).copied().unwrap_or(false)).collect())
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		let state = self.state.display();
+
+		match action {
+			Action::On => return format!("set {} port {} on in {}", self.serial_number, self.port_number, state),
+			Action::Off => return format!("set {} port {} off in {}", self.serial_number, self.port_number, state),
+			Action::Status => return format!("read {} port {} from {}", self.serial_number, self.port_number, state),
+			Action::List => return format!("read the hubs in {}", state),
+		}
+	}
+}
@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex, mpsc},
+	thread,
+	time::{Duration, Instant},
+};
+use log::debug;
+
+use crate::boards::Board;
+
+#[derive(Clone, Debug)]
+pub struct PowerState {
+	pub powered: Option<bool>,
+	pub error: Option<String>,
+	pub updated: Instant,
+}
+
+/// boards sorted by the hub they hang off, in the order they came in
+pub fn by_hub(boards: &[Board]) -> Vec<(String, Vec<&Board>)>
+{
+	let mut hubs: Vec<(String, Vec<&Board>)> = Vec::new();
+
+	for board in boards.iter() {
+		let hub = board.power.hub();
+		match hubs.iter_mut().find(|(name, _)| return *name == hub) {
+			Some((_, members)) => members.push(board),
+			None => hubs.push((hub, vec![board])),
+		}
+	}
+
+	return hubs
+}
+
+/// Read the power state of every board, going to each hub only the once no
+/// matter how many of the boards hang off it.
+pub fn poll(boards: &[Board]) -> HashMap<String, PowerState>
+{
+	let mut states = HashMap::new();
+
+	for (hub, members) in by_hub(boards).iter() {
+		let ports: Vec<String> = members
+			.iter()
+			.map(|board| return board.yk_port_number.clone())
+			.collect();
+
+		debug!("polling {} for {} board(s)", hub, members.len());
+		let result = members[0].power.port_states(&ports);
+		let updated = Instant::now();
+
+		for (i, board) in members.iter().enumerate() {
+			let state = match &result {
+				Ok(powered) => PowerState {
+					powered: powered.get(i).copied(),
+					error: None,
+					updated,
+				},
+				Err(e) => PowerState {
+					powered: None,
+					error: Some(e.to_string()),
+					updated,
+				},
+			};
+			states.insert(board.name.clone(), state);
+		}
+	}
+
+	return states
+}
+
+/// Keeps the power state of a set of boards up to date from a background
+/// thread, so that readers never have to wait on a hub.
+pub struct Poller {
+	states: Arc<Mutex<HashMap<String, PowerState>>>,
+	wake: mpsc::Sender<()>,
+}
+
+impl Poller {
+	pub fn start(boards: Vec<Board>, interval: Duration) -> Poller
+	{
+		let states = Arc::new(Mutex::new(HashMap::new()));
+		let (wake, woken) = mpsc::channel();
+		let shared = states.clone();
+
+		thread::spawn(move || {
+			loop {
+				let polled = poll(&boards);
+				shared.lock().unwrap().extend(polled);
+
+				/* either the interval is up or someone wants it now */
+				if let Err(mpsc::RecvTimeoutError::Disconnected) = woken.recv_timeout(interval) {
+					return;
+				}
+			}
+		});
+
+		return Poller { states, wake }
+	}
+
+	/// the last known state of a board, None if it has not been polled yet
+	pub fn get(&self, board_name: &str) -> Option<PowerState>
+	{
+		return self.states.lock().unwrap().get(board_name).cloned()
+	}
+
+	/// poll again straight away rather than waiting for the interval
+	pub fn refresh(&self)
+	{
+		let _ = self.wake.send(());
+	}
+}
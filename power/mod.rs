@@ -0,0 +1,336 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{
+	fmt,
+	io::Read,
+	process::{Command, Output, Stdio},
+	sync::{Arc, atomic::{AtomicBool, Ordering}},
+	thread,
+	time::{Duration, Instant},
+};
+use crate::{ykcmd, ykhid};
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+/* long enough off for a board to lose power properly */
+const POWER_CYCLE_PAUSE: Duration = Duration::from_millis(1000);
+
+/* set once from the command line, before any board gets loaded */
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// print power & uart operations rather than carrying them out
+pub fn set_dry_run(dry_run: bool)
+{
+	DRY_RUN.store(dry_run, Ordering::SeqCst);
+}
+
+pub fn dry_run() -> bool
+{
+	return DRY_RUN.load(Ordering::SeqCst)
+}
+
+#[derive(Debug)]
+pub struct HubNotFoundError {
+	details: String
+}
+
+impl HubNotFoundError {
+	pub fn new(serial_number: &str) -> HubNotFoundError {
+		return HubNotFoundError{details: serial_number.to_string()}
+	}
+}
+
+impl fmt::Display for HubNotFoundError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "board with serial {} not found", self.details)
+	}
+}
+
+impl std::error::Error for HubNotFoundError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>>
+{
+	return thread::spawn(move || {
+		let mut data = Vec::new();
+		let _ = pipe.read_to_end(&mut data);
+		return data
+	})
+}
+
+/// Run a tool that drives a switch and hand back how it went. Arguments go
+/// straight to the process as argv, there is no shell in the way, and it
+/// gets killed if it isn't done within timeout.
+pub fn execute(argv: &[String], timeout: Duration) -> Result<Output, String>
+{
+	let invocation = argv.join(" ");
+	let mut child = Command::new(&argv[0])
+		.args(&argv[1..])
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.map_err(|e| return format!("could not run {}: {}", invocation, e))?;
+
+	/* read as it goes, or a chatty tool fills the pipe and never finishes */
+	let stdout = read_all(child.stdout.take().unwrap());
+	let stderr = read_all(child.stderr.take().unwrap());
+	let deadline = Instant::now() + timeout;
+
+	let status = loop {
+		match child.try_wait() {
+			Ok(Some(status)) => break status,
+			Ok(None) if Instant::now() >= deadline => {
+				let _ = child.kill();
+				let _ = child.wait();
+				return Err(format!("{} timed out after {}s", invocation, timeout.as_secs()))
+			},
+			Ok(None) => thread::sleep(WAIT_INTERVAL),
+			Err(e) => return Err(format!("{}: {}", invocation, e)),
+		}
+	};
+
+	return Ok(Output {
+		status,
+		stdout: stdout.join().unwrap_or_default(),
+		stderr: stderr.join().unwrap_or_default(),
+	})
+}
+
+/// what to say about a tool that didn't exit cleanly
+pub fn failure(argv: &[String], output: &Output) -> String
+{
+	return format!("{} failed ({}): {}", argv.join(" "), output.status,
+		       String::from_utf8_lossy(&output.stderr).trim())
+}
+
+/// The operations on a switch, for explaining what one would do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+	On,
+	Off,
+	Status,
+	List,
+}
+
+#[derive(Debug)]
+pub struct DryRunError {
+	details: String
+}
+
+impl DryRunError {
+	pub fn new(msg: &str) -> DryRunError {
+		return DryRunError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for DryRunError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "dry run: {}", self.details)
+	}
+}
+
+impl std::error::Error for DryRunError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+/// Something that can switch power to a single board, be that a port on a
+/// usb hub, a relay or anything else. Each board holds its own instance, so
+/// implementations carry whatever addressing (serial, port...) they need.
+pub trait PowerBackend: fmt::Debug + Send + Sync {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>;
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>;
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>;
+	/// serial numbers of every switch of this kind currently attached
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+	/// names the switch this board hangs off, boards sharing one can have
+	/// their status read together with port_states()
+	fn hub(&self) -> String;
+	/// status of several ports on this board's switch, in the same order,
+	/// looking the switch itself up only the once
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>;
+	/// what carrying out action comes down to, exactly, for --dry-run
+	fn explain(&self, action: Action) -> String;
+	/// off, for when whoever is asking has found the switch already, so
+	/// each of the boards on it needn't go looking again
+	fn off_found(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.off()
+	}
+	/// off and on again, for switches that can't do it in one go
+	fn reboot(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return power_cycle(self)
+	}
+	fn explain_reboot(&self) -> String
+	{
+		return explain_power_cycle(self)
+	}
+}
+
+pub fn power_cycle<B: PowerBackend + ?Sized>(backend: &B) -> Result<(), Box<dyn std::error::Error>>
+{
+	backend.off()?;
+	thread::sleep(POWER_CYCLE_PAUSE);
+	return backend.on()
+}
+
+pub fn explain_power_cycle<B: PowerBackend + ?Sized>(backend: &B) -> String
+{
+	return format!("{}, then {}", backend.explain(Action::Off), backend.explain(Action::On))
+}
+
+/// Stand-in for boards whose "type" no backend knows how to drive, so that
+/// the rest of the config still loads and the error only shows up on use.
+#[derive(Debug)]
+pub struct Unsupported {
+	power_source: String,
+}
+
+impl Unsupported {
+	pub fn new(power_source: &str) -> Unsupported {
+		return Unsupported{power_source: power_source.to_string()}
+	}
+
+	fn error(&self) -> Box<dyn std::error::Error> {
+		return Box::new(ykcmd::YkmdError::new(&format!(
+			"Unsupported yk board type {}", self.power_source)))
+	}
+}
+
+impl PowerBackend for Unsupported {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return Err(self.error())
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return Err(self.error())
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		return Err(self.error())
+	}
+
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		return Err(self.error())
+	}
+
+	fn hub(&self) -> String
+	{
+		return format!("unsupported:{}", self.power_source)
+	}
+
+	fn port_states(&self, _ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		return Err(self.error())
+	}
+
+	fn explain(&self, _action: Action) -> String
+	{
+		return format!("nothing, {} is not a supported type", self.power_source)
+	}
+}
+
+/// Goes in front of every board's backend under --dry-run, saying what it
+/// would have done instead. Nothing gets read either, so there is no telling
+/// whether anything is on.
+#[derive(Debug)]
+pub struct DryRun {
+	board: String,
+	backend: Arc<dyn PowerBackend>,
+}
+
+impl DryRun {
+	pub fn new(board: &str, backend: Arc<dyn PowerBackend>) -> DryRun {
+		return DryRun{board: board.to_string(), backend}
+	}
+
+	fn would(&self, action: Action) -> Box<dyn std::error::Error>
+	{
+		let what = match action {
+			Action::On => "power on",
+			Action::Off => "power off",
+			Action::Status => "check power",
+			Action::List => "look for switches",
+		};
+		println!("{}: would {}: {}", self.board, what, self.backend.explain(action));
+		return Box::new(DryRunError::new("not asked"))
+	}
+}
+
+impl PowerBackend for DryRun {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		self.would(Action::On);
+		return Ok(())
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		self.would(Action::Off);
+		return Ok(())
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		return Err(self.would(Action::Status))
+	}
+
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		return Err(self.would(Action::List))
+	}
+
+	fn hub(&self) -> String
+	{
+		return self.backend.hub()
+	}
+
+	fn port_states(&self, _ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		return Err(self.would(Action::Status))
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		return self.backend.explain(action)
+	}
+
+	fn reboot(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		println!("{}: would power cycle: {}", self.board, self.backend.explain_reboot());
+		return Ok(())
+	}
+
+	fn explain_reboot(&self) -> String
+	{
+		return self.backend.explain_reboot()
+	}
+}
+
+/// Map the "type" and "driver" of a board in the config to the backend that
+/// drives it. New kinds of switch only need a match arm here.
+pub fn backend_for(power_source: &str, driver: &str, serial_number: &str, port_number: &str)
+-> Arc<dyn PowerBackend>
+{
+	match (power_source, driver) {
+		("usb", "ykcmd") => return Arc::new(ykcmd::Ykush::new(serial_number, port_number)),
+		("relay", "ykcmd") => return Arc::new(ykcmd::Ykur::new(serial_number, port_number)),
+		("usb" | "relay", "hidraw") => {
+			return Arc::new(ykhid::Yepkit::new(power_source, serial_number, port_number,
+							   Arc::new(ykhid::Hidraw::default())))
+		},
+		_ => return Arc::new(Unsupported::new(&format!("{} ({})", power_source, driver))),
+	}
+}
@@ -0,0 +1,302 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{
+	fmt, fs, io::{self, Read, Write},
+	os::unix::{net::UnixStream, process::CommandExt},
+	path::{self, PathBuf},
+	process::{Child, Command, Stdio},
+	thread,
+	time::{Duration, Instant},
+};
+use log::debug;
+use crate::config::{self, QemuConfig};
+use crate::power::{Action, PowerBackend};
+
+/*
+ * A virtual board is a QEMU process. Powering it on starts QEMU, paused, with
+ * its first serial port & its monitor on unix sockets. The pid goes in a
+ * pidfile next to them, so that later runs of lab can tell whether it is on
+ * and kill it again. Anything QEMU prints itself ends up in a log alongside.
+ */
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+/* how long QEMU gets to go away when asked, and then when told */
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/* a chance for consoles to connect before anything gets printed & missed */
+const CONNECT_GRACE: Duration = Duration::from_millis(300);
+
+#[derive(Debug)]
+pub struct QemuError {
+	details: String
+}
+
+impl QemuError {
+	pub fn new(msg: &str) -> QemuError {
+		return QemuError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for QemuError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "QEMU failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for QemuError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+#[derive(Debug)]
+pub struct Qemu {
+	name: String,
+	command: Vec<String>,
+	dir: PathBuf,
+}
+
+impl Qemu {
+	pub fn new(name: &str, config: &QemuConfig) -> Qemu
+	{
+		/* absolute, since it is how a running QEMU gets recognised */
+		let dir = path::absolute(&config.dir).unwrap_or_else(|_| return config.dir.clone());
+
+		return Qemu {
+			name: name.to_string(),
+			command: config.command.clone(),
+			dir,
+		}
+	}
+
+	/// where the board's uart is, for as long as QEMU is running
+	pub fn socket(&self) -> PathBuf
+	{
+		return self.dir.join(format!("{}.sock", self.name))
+	}
+
+	fn monitor(&self) -> PathBuf
+	{
+		return self.dir.join(format!("{}.monitor", self.name))
+	}
+
+	fn pidfile(&self) -> PathBuf
+	{
+		return self.dir.join(format!("{}.pid", self.name))
+	}
+
+	fn log(&self) -> PathBuf
+	{
+		return self.dir.join(format!("{}.log", self.name))
+	}
+
+	/// the pid of this board's QEMU, if it is running
+	fn running(&self) -> Option<libc::pid_t>
+	{
+		let pid: libc::pid_t = fs::read_to_string(self.pidfile()).ok()?.trim().parse().ok()?;
+
+		/* started by this lab, it would hang around as a zombie until reaped */
+		let mut status = 0;
+		if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == pid {
+			return None
+		}
+
+		/* the pid may well have been handed on to something else since */
+		let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+		let socket = self.socket().to_string_lossy().into_owned();
+		match String::from_utf8_lossy(&cmdline).contains(&socket) {
+			true => return Some(pid),
+			false => return None,
+		}
+	}
+
+	/// the last thing QEMU had to say, which is usually why it stopped
+	fn last_words(&self) -> String
+	{
+		let log = fs::read_to_string(self.log()).unwrap_or_default();
+
+		match log.lines().rev().find(|line| return !line.trim().is_empty()) {
+			Some(line) => return line.trim().to_string(),
+			None => return format!("exited, see {}", self.log().display()),
+		}
+	}
+
+	fn error(&self, what: &str) -> Box<dyn std::error::Error>
+	{
+		return Box::new(QemuError::new(&format!("{}: {}", self.name, what)))
+	}
+
+	/// Wait for a freshly started QEMU to be listening on its monitor, which
+	/// means its serial port is there too
+	fn wait_for(&self, child: &mut Child) -> Result<(), Box<dyn std::error::Error>>
+	{
+		let deadline = Instant::now() + START_TIMEOUT;
+
+		loop {
+			if child.try_wait()?.is_some() {
+				return Err(self.error(&self.last_words()))
+			}
+			if UnixStream::connect(self.monitor()).is_ok() {
+				return Ok(())
+			}
+			if Instant::now() >= deadline {
+				let _ = child.kill();
+				let _ = child.wait();
+				return Err(self.error(&format!("nothing listening on {} after {}s",
+							       self.monitor().display(),
+							       START_TIMEOUT.as_secs())))
+			}
+			thread::sleep(POLL_INTERVAL);
+		}
+	}
+
+	/// run a command on the monitor, waiting for the prompt to come back
+	fn monitor_command(&self, command: &str) -> Result<(), Box<dyn std::error::Error>>
+	{
+		let mut monitor = UnixStream::connect(self.monitor())
+			.map_err(|e| return self.error(&format!("{}: {}", self.monitor().display(), e)))?;
+
+		monitor.set_read_timeout(Some(START_TIMEOUT))?;
+		monitor.write_all(format!("{}\n", command).as_bytes())?;
+
+		/* one prompt on connecting, another once the command is done */
+		let mut answer = Vec::new();
+		let mut data = [0u8; 256];
+		while answer.windows(6).filter(|prompt| return prompt == b"(qemu)").count() < 2 {
+			match monitor.read(&mut data) {
+				Ok(0) => break,
+				Ok(read) => answer.extend_from_slice(&data[..read]),
+				Err(e) => return Err(self.error(&format!("monitor: {}", e))),
+			}
+		}
+
+		return Ok(())
+	}
+
+	/// ask nicely, then less so, until it is gone
+	fn stop(&self, pid: libc::pid_t) -> Result<(), Box<dyn std::error::Error>>
+	{
+		for signal in [libc::SIGTERM, libc::SIGKILL] {
+			if unsafe { libc::kill(pid, signal) } != 0 {
+				return Err(self.error(&format!("killing {}: {}", pid, io::Error::last_os_error())))
+			}
+
+			let deadline = Instant::now() + STOP_TIMEOUT;
+			while Instant::now() < deadline {
+				if self.running().is_none() {
+					return Ok(())
+				}
+				thread::sleep(POLL_INTERVAL);
+			}
+		}
+
+		return Err(self.error(&format!("pid {} will not go away", pid)))
+	}
+
+	/// what lab adds to the command itself
+	fn extra_args(&self) -> Vec<String>
+	{
+		return vec![
+			"-S".to_string(),
+			"-serial".to_string(),
+			format!("unix:{},server=on,wait=off", self.socket().display()),
+			"-monitor".to_string(),
+			format!("unix:{},server=on,wait=off", self.monitor().display()),
+		]
+	}
+
+	fn tidy(&self)
+	{
+		for file in [self.socket(), self.monitor(), self.pidfile()] {
+			let _ = fs::remove_file(file);
+		}
+	}
+}
+
+impl PowerBackend for Qemu {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		if self.running().is_some() {
+			return Ok(())
+		}
+
+		fs::create_dir_all(&self.dir)
+			.map_err(|e| return self.error(&format!("{}: {}", self.dir.display(), e)))?;
+		self.tidy();
+
+		let log = fs::File::create(self.log())
+			.map_err(|e| return self.error(&format!("{}: {}", self.log().display(), e)))?;
+		let mut child = Command::new(&self.command[0])
+			.args(&self.command[1..])
+			.args(self.extra_args())
+			.stdin(Stdio::null())
+			.stdout(log.try_clone()?)
+			.stderr(log)
+			/* out of the way of a ^C to lab, it stays on after lab exits */
+			.process_group(0)
+			.spawn()
+			.map_err(|e| return self.error(&format!("{}: {}", self.command[0], e)))?;
+		fs::write(self.pidfile(), format!("{}\n", child.id()))?;
+		debug!("{} started as pid {}", self.name, child.id());
+
+		if let Err(e) = self.wait_for(&mut child) {
+			self.tidy();
+			return Err(e)
+		}
+		thread::sleep(CONNECT_GRACE);
+		self.monitor_command("cont")?;
+
+		return Ok(())
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		if let Some(pid) = self.running() {
+			self.stop(pid)?;
+			debug!("{} (pid {}) stopped", self.name, pid);
+		}
+		self.tidy();
+
+		return Ok(())
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		return Ok(self.running().is_some())
+	}
+
+	/// There is no hub to be attached, so boards are there as long as QEMU
+	/// itself is. They have no serial either, hence "n/a".
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		match config::on_path(&self.command[0]) {
+			true => return Ok(vec!["n/a".to_string()]),
+			false => return Ok(Vec::new()),
+		}
+	}
+
+	/// each virtual board is a hub of its own, so they all start at once
+	fn hub(&self) -> String
+	{
+		return format!("qemu:{}", self.name)
+	}
+
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		let on = self.running().is_some();
+
+		return Ok(ports.iter().map(|_| return on).collect())
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		match action {
+			Action::On => return [self.command.clone(), self.extra_args()].concat().join(" "),
+			Action::Off => return format!("kill the pid in {}", self.pidfile().display()),
+			Action::Status => return format!("check the pid in {} is running", self.pidfile().display()),
+			Action::List => return format!("look for {}", self.command[0]),
+		}
+	}
+}
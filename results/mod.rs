@@ -0,0 +1,543 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{fmt, fs, fmt::Write, path::Path};
+
+use crate::boards::Board;
+use crate::boottest::Report;
+use crate::ykcmd;
+
+#[derive(Debug)]
+pub struct ResultsError {
+	details: String
+}
+
+impl ResultsError {
+	pub fn new(msg: &str) -> ResultsError {
+		return ResultsError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for ResultsError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Writing results failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for ResultsError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+enum State {
+	Passed,
+	Failed(String),
+	/// never got that far
+	Skipped,
+}
+
+/// how one of a board's boot steps went, for the steps that expect anything
+struct Step {
+	name: String,
+	state: State,
+	/// seconds since power on
+	after: Option<f64>,
+	/// seconds since the step before
+	took: Option<f64>,
+}
+
+/// Line a board's boot steps up with the stages that matched, the first
+/// one that didn't getting the blame should the test have failed.
+fn steps(board: &Board, report: &Report) -> Vec<Step>
+{
+	let error = report.result.clone().err();
+	let mut blamed = false;
+	let mut previous = 0.0;
+	let mut steps = Vec::new();
+
+	let expecting = board.boot_steps.iter().filter(|step| return step.expect.is_some());
+	for (i, step) in expecting.enumerate() {
+		let mut result = Step {
+			name: step.label(),
+			state: State::Skipped,
+			after: None,
+			took: None,
+		};
+
+		match (report.stages.get(i), &error) {
+			(Some(stage), _) => {
+				result.after = Some(stage.seconds);
+				result.took = Some(stage.seconds - previous);
+				previous = stage.seconds;
+
+				result.state = match stage.baseline {
+					Some(baseline) if stage.slow => State::Failed(format!(
+						"took {:.1}s, {:.1}s in the baseline", stage.seconds, baseline)),
+					_ => State::Passed,
+				};
+			},
+			(None, Some(error)) if !blamed => {
+				blamed = true;
+				result.state = State::Failed(error.clone());
+			},
+			(None, _) => (),
+		}
+
+		steps.push(result);
+	}
+
+	return steps
+}
+
+fn write_file(path: &Path, text: &str) -> Result<(), Box<dyn std::error::Error>>
+{
+	fs::write(path, text)
+		.map_err(|e| return ResultsError::new(&format!("{}: {}", path.display(), e)))?;
+
+	return Ok(())
+}
+
+/// fit for an attribute or text, minus anything XML 1.0 won't have at all
+fn xml_escape(text: &str) -> String
+{
+	let mut escaped = String::new();
+
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&apos;"),
+			'\t' | '\n' | '\r' => escaped.push(c),
+			c if c.is_control() => (),
+			c => escaped.push(c),
+		}
+	}
+
+	return escaped
+}
+
+/// One testsuite with a testcase per board. JUnit has no notion of steps
+/// within a testcase, so they go in as properties & in the output.
+pub fn write_junit(path: &Path, boards: &[Board], reports: &[Report])
+-> Result<(), Box<dyn std::error::Error>>
+{
+	let failures = reports.iter().filter(|report| return report.result.is_err()).count();
+	let time: f64 = reports.iter().map(|report| return report.duration.as_secs_f64()).sum();
+	let mut xml = String::new();
+
+	writeln!(xml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+	writeln!(xml, "<testsuites name=\"lab\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+		 reports.len(), failures, time)?;
+	writeln!(xml, "  <testsuite name=\"boot-test\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+		 reports.len(), failures, time)?;
+
+	for (board, report) in boards.iter().zip(reports.iter()) {
+		let name = xml_escape(&report.name());
+		let steps = steps(board, report);
+		let mut output = String::new();
+
+		writeln!(xml, "    <testcase name=\"{}\" classname=\"boot-test.{}\" time=\"{:.3}\">",
+			 name, xml_escape(&report.board), report.duration.as_secs_f64())?;
+
+		writeln!(xml, "      <properties>")?;
+		if let Some(transcript) = &report.transcript {
+			writeln!(xml, "        <property name=\"transcript\" value=\"{}\"/>",
+				 xml_escape(&transcript.display().to_string()))?;
+		}
+		for step in steps.iter() {
+			let value = match (&step.state, step.after) {
+				(State::Passed, Some(after)) => format!("passed after {:.3}s", after),
+				(State::Failed(_), Some(after)) => format!("failed after {:.3}s", after),
+				(State::Failed(_), None) => "failed".to_string(),
+				_ => "skipped".to_string(),
+			};
+			writeln!(xml, "        <property name=\"step.{}\" value=\"{}\"/>",
+				 xml_escape(&step.name), value)?;
+		}
+		writeln!(xml, "      </properties>")?;
+
+		for step in steps.iter() {
+			match (&step.state, step.after, step.took) {
+				(State::Passed, Some(after), Some(took)) => writeln!(output,
+					"ok {} after {:.3}s, took {:.3}s", step.name, after, took)?,
+				(State::Failed(reason), _, _) => writeln!(output,
+					"not ok {}: {}", step.name, reason)?,
+				_ => writeln!(output, "skipped {}", step.name)?,
+			}
+		}
+		if let Some(transcript) = &report.transcript {
+			writeln!(output, "transcript: {}", transcript.display())?;
+			writeln!(output, "[[ATTACHMENT|{}]]", transcript.display())?;
+		}
+
+		if let Err(e) = &report.result {
+			let message = xml_escape(e);
+			writeln!(xml, "      <failure message=\"{}\" type=\"boot-test\">{}</failure>",
+				 message, message)?;
+		}
+
+		writeln!(xml, "      <system-out>{}</system-out>", xml_escape(&output))?;
+		writeln!(xml, "    </testcase>")?;
+	}
+
+	writeln!(xml, "  </testsuite>")?;
+	writeln!(xml, "</testsuites>")?;
+
+	return write_file(path, &xml)
+}
+
+/// A board per row and a column per boot stage, with how long after power
+/// on each one showed up, or how it went if it never did
+pub fn print_matrix(boards: &[Board], reports: &[Report])
+{
+	let all_steps: Vec<Vec<Step>> = boards
+		.iter()
+		.zip(reports.iter())
+		.map(|(board, report)| return steps(board, report))
+		.collect();
+
+	/* boards needn't share stages, so every one any board has gets a column */
+	let mut stages: Vec<String> = Vec::new();
+	for step in all_steps.iter().flatten() {
+		if !stages.contains(&step.name) {
+			stages.push(step.name.clone());
+		}
+	}
+
+	let mut header = vec!["BOARD".to_string()];
+	header.extend(stages.iter().cloned());
+	header.push("RESULT".to_string());
+	let mut rows = vec![header];
+
+	for (report, steps) in reports.iter().zip(all_steps.iter()) {
+		let mut row = vec![report.name()];
+
+		for stage in stages.iter() {
+			let cell = match steps.iter().find(|step| return step.name == *stage) {
+				None => "".to_string(),
+				Some(step) => match (&step.state, step.after) {
+					(State::Passed, Some(after)) => format!("{:.1}s", after),
+					(State::Failed(_), Some(after)) => format!("slow {:.1}s", after),
+					(State::Failed(_), None) => "FAILED".to_string(),
+					_ => "-".to_string(),
+				},
+			};
+			row.push(cell);
+		}
+
+		row.push(match report.result {
+			Ok(()) => "passed".to_string(),
+			Err(_) => "failed".to_string(),
+		});
+		rows.push(row);
+	}
+
+	ykcmd::print_table(&rows);
+}
+
+/// the middle value, or the mean of the middle two, of some sorted values
+fn median(sorted: &[f64]) -> f64
+{
+	let middle = sorted.len() / 2;
+
+	if sorted.len() % 2 == 0 {
+		return (sorted[middle - 1] + sorted[middle]) / 2.0
+	}
+
+	return sorted[middle]
+}
+
+/// How a soak test went for each board: how often it failed & where, and
+/// the spread of times each stage showed up at.
+pub fn print_soak(boards: &[Board], per_board: &[Vec<Report>])
+{
+	let mut rows = vec![
+		["BOARD", "STAGE", "RUNS", "MIN", "MEDIAN", "MAX"]
+			.iter()
+			.map(|heading| return heading.to_string())
+			.collect(),
+	];
+
+	for (board, reports) in boards.iter().zip(per_board.iter()) {
+		let failed = reports.iter().filter(|report| return report.result.is_err()).count();
+		let mut failed_at: Vec<(String, usize)> = Vec::new();
+		let mut times: Vec<(String, Vec<f64>)> = Vec::new();
+
+		for report in reports.iter() {
+			let steps = steps(board, report);
+
+			for step in steps.iter() {
+				if let Some(after) = step.after {
+					match times.iter_mut().find(|(name, _)| return *name == step.name) {
+						Some((_, seen)) => seen.push(after),
+						None => times.push((step.name.clone(), vec![after])),
+					}
+				}
+			}
+
+			if report.result.is_err() {
+				/* it got through every stage, so it was the shutdown that broke */
+				let stage = steps
+					.iter()
+					.find(|step| return matches!(step.state, State::Failed(_)))
+					.map_or("shutdown", |step| return step.name.as_str());
+
+				match failed_at.iter_mut().find(|(name, _)| return name == stage) {
+					Some((_, count)) => *count += 1,
+					None => failed_at.push((stage.to_string(), 1)),
+				}
+			}
+		}
+
+		println!("{}: {} of {} run(s) failed ({:.1}%)", board.name, failed, reports.len(),
+			 100.0 * failed as f64 / reports.len().max(1) as f64);
+		for (stage, count) in failed_at.iter() {
+			println!("{}: failed at {} {} time(s)", board.name, stage, count);
+		}
+
+		for (stage, mut seen) in times {
+			seen.sort_by(|a, b| return a.total_cmp(b));
+			rows.push(vec![
+				board.name.clone(),
+				stage,
+				seen.len().to_string(),
+				format!("{:.1}s", seen[0]),
+				format!("{:.1}s", median(&seen)),
+				format!("{:.1}s", seen[seen.len() - 1]),
+			]);
+		}
+	}
+
+	println!();
+	ykcmd::print_table(&rows);
+}
+
+/// a quoted yaml string, which a json string happens to be
+fn yaml_string(text: &str) -> String
+{
+	return serde_json::Value::String(text.to_string()).to_string()
+}
+
+/// TAP 14, with each board a subtest made up of its boot steps
+pub fn write_tap(path: &Path, boards: &[Board], reports: &[Report])
+-> Result<(), Box<dyn std::error::Error>>
+{
+	let mut tap = String::new();
+
+	writeln!(tap, "TAP version 14")?;
+	writeln!(tap, "1..{}", reports.len())?;
+
+	for (i, (board, report)) in boards.iter().zip(reports.iter()).enumerate() {
+		let steps = steps(board, report);
+
+		writeln!(tap, "# Subtest: {}", report.name())?;
+		writeln!(tap, "    1..{}", steps.len())?;
+
+		for (j, step) in steps.iter().enumerate() {
+			match &step.state {
+				State::Passed => writeln!(tap, "    ok {} - {}", j + 1, step.name)?,
+				State::Failed(_) => writeln!(tap, "    not ok {} - {}", j + 1, step.name)?,
+				State::Skipped => writeln!(tap, "    ok {} - {} # SKIP never got this far",
+							   j + 1, step.name)?,
+			}
+
+			if matches!(step.state, State::Skipped) {
+				continue;
+			}
+
+			writeln!(tap, "      ---")?;
+			if let State::Failed(reason) = &step.state {
+				writeln!(tap, "      message: {}", yaml_string(reason))?;
+			}
+			if let Some(after) = step.after {
+				writeln!(tap, "      after: {:.3}", after)?;
+			}
+			if let Some(took) = step.took {
+				writeln!(tap, "      duration_ms: {:.0}", took * 1000.0)?;
+			}
+			writeln!(tap, "      ...")?;
+		}
+
+		match &report.result {
+			Ok(()) => writeln!(tap, "ok {} - {}", i + 1, report.name())?,
+			Err(_) => writeln!(tap, "not ok {} - {}", i + 1, report.name())?,
+		}
+
+		writeln!(tap, "  ---")?;
+		if let Err(e) = &report.result {
+			writeln!(tap, "  message: {}", yaml_string(e))?;
+		}
+		writeln!(tap, "  duration_ms: {}", report.duration.as_millis())?;
+		if let Some(transcript) = &report.transcript {
+			writeln!(tap, "  transcript: {}", yaml_string(&transcript.display().to_string()))?;
+		}
+		writeln!(tap, "  ...")?;
+	}
+
+	return write_file(path, &tap)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{path::PathBuf, time::Duration};
+	use crate::boottest::StageTime;
+	use crate::config::BootStep;
+
+	const REASON: &str = r#"Boot test failed: Linux: saw "Oops: <&'>""#;
+
+	fn step(name: &str) -> BootStep
+	{
+		return BootStep {
+			name: Some(name.to_string()),
+			expect: Some(name.to_string()),
+			send: None,
+			timeout: 60,
+			fail: None,
+			retry: None,
+		}
+	}
+
+	fn stage(name: &str, seconds: f64) -> StageTime
+	{
+		return StageTime {
+			name: name.to_string(),
+			seconds,
+			baseline: None,
+			slow: false,
+		}
+	}
+
+	/// one board that passed & one that never got as far as Linux
+	fn results() -> (Vec<Board>, Vec<Report>)
+	{
+		let boards: Vec<Board> = ["good", "bad"]
+			.iter()
+			.map(|name| {
+				return Board {
+					name: name.to_string(),
+					boot_steps: vec![step("U-Boot"), step("Linux")],
+					..Board::default()
+				}
+			})
+			.collect();
+		let reports = vec![
+			Report {
+				board: "good".to_string(),
+				run: None,
+				result: Ok(()),
+				transcript: Some(PathBuf::from("/tmp/t/good.log")),
+				stages: vec![stage("U-Boot", 1.5), stage("Linux", 4.25)],
+				duration: Duration::from_secs(10),
+			},
+			Report {
+				board: "bad".to_string(),
+				run: None,
+				result: Err(REASON.to_string()),
+				transcript: None,
+				stages: vec![stage("U-Boot", 2.0)],
+				duration: Duration::from_millis(5500),
+			},
+		];
+
+		return (boards, reports)
+	}
+
+	/// a results writer, junit or tap
+	type Writer = fn(&Path, &[Board], &[Report]) -> Result<(), Box<dyn std::error::Error>>;
+
+	fn written(test: &str, write: Writer) -> String
+	{
+		let path = std::env::temp_dir().join(format!("lab-results-{}-{}", test, std::process::id()));
+		let (boards, reports) = results();
+		write(&path, &boards, &reports).unwrap();
+		let text = fs::read_to_string(&path).unwrap();
+		let _ = fs::remove_file(path);
+
+		return text
+	}
+
+	#[test]
+	fn junit_for_a_pass_and_a_failure()
+	{
+		let failure = "Boot test failed: Linux: saw &quot;Oops: &lt;&amp;&apos;&gt;&quot;";
+		let expected = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="lab" tests="2" failures="1" time="15.500">
+  <testsuite name="boot-test" tests="2" failures="1" time="15.500">
+    <testcase name="good" classname="boot-test.good" time="10.000">
+      <properties>
+        <property name="transcript" value="/tmp/t/good.log"/>
+        <property name="step.U-Boot" value="passed after 1.500s"/>
+        <property name="step.Linux" value="passed after 4.250s"/>
+      </properties>
+      <system-out>ok U-Boot after 1.500s, took 1.500s
+ok Linux after 4.250s, took 2.750s
+transcript: /tmp/t/good.log
+[[ATTACHMENT|/tmp/t/good.log]]
+</system-out>
+    </testcase>
+    <testcase name="bad" classname="boot-test.bad" time="5.500">
+      <properties>
+        <property name="step.U-Boot" value="passed after 2.000s"/>
+        <property name="step.Linux" value="failed"/>
+      </properties>
+      <failure message="{failure}" type="boot-test">{failure}</failure>
+      <system-out>ok U-Boot after 2.000s, took 2.000s
+not ok Linux: {failure}
+</system-out>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#);
+
+		assert_eq!(written("junit", write_junit), expected);
+	}
+
+	#[test]
+	fn tap_for_a_pass_and_a_failure()
+	{
+		let expected = r#"TAP version 14
+1..2
+# Subtest: good
+    1..2
+    ok 1 - U-Boot
+      ---
+      after: 1.500
+      duration_ms: 1500
+      ...
+    ok 2 - Linux
+      ---
+      after: 4.250
+      duration_ms: 2750
+      ...
+ok 1 - good
+  ---
+  duration_ms: 10000
+  transcript: "/tmp/t/good.log"
+  ...
+# Subtest: bad
+    1..2
+    ok 1 - U-Boot
+      ---
+      after: 2.000
+      duration_ms: 2000
+      ...
+    not ok 2 - Linux
+      ---
+      message: "Boot test failed: Linux: saw \"Oops: <&'>\""
+      ...
+not ok 2 - bad
+  ---
+  message: "Boot test failed: Linux: saw \"Oops: <&'>\""
+  duration_ms: 5500
+  ...
+"#;
+
+		assert_eq!(written("tap", write_tap), expected);
+	}
+}
@@ -0,0 +1,733 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use regex::Regex;
+use std::{
+	ffi::CStr,
+	fmt, fs,
+	io::{self, Read, Write},
+	os::unix::{fs::{OpenOptionsExt, symlink}, io::{AsRawFd, FromRawFd}},
+	path::{self, PathBuf},
+	thread,
+	time::{Duration, Instant, SystemTime},
+};
+use log::{debug, error};
+use crate::boards::{self, ConfigParsingError};
+use crate::config::{self, Fault, SimConfig};
+use crate::power::{Action, PowerBackend};
+
+/*
+ * A simulated board is two halves. Its power is a file saying "on" or "off",
+ * which is all lab on & off touch. lab sim is the board itself: it sits on a
+ * pty, symlinked to where the board's uart is expected, and plays a boot log
+ * back whenever the file says it is on. Once at the login prompt it takes the
+ * board's own user & password, and a shell that knows poweroff & reboot.
+ */
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/* how long a wrong password takes to get turned down, like login does */
+const LOGIN_DELAY: Duration = Duration::from_secs(1);
+/* "{board}" gets replaced by the board's name */
+const DEFAULT_LOG: &str = "\
+OpenSBI v1.3
+U-Boot 2023.10 (lab sim)
+Hit any key to stop autoboot:  2
+Hit any key to stop autoboot:  1
+Hit any key to stop autoboot:  0
+Starting kernel ...
+[    0.000000] Linux version 6.6.0 (lab@sim) #1 SMP
+[    0.912345] Run /sbin/init as init process
+Welcome to Fedora Linux!
+{board} login: ";
+const PANIC: [&str; 3] = [
+	"[    1.234567] Kernel panic - not syncing: Attempted to kill init! exitcode=0x0000000b",
+	"[    1.234601] CPU: 0 PID: 1 Comm: init Not tainted 6.6.0 #1",
+	"[    1.234689] ---[ end Kernel panic - not syncing: Attempted to kill init! exitcode=0x0000000b ]---",
+];
+
+#[derive(Debug)]
+pub struct SimError {
+	details: String
+}
+
+impl SimError {
+	pub fn new(msg: &str) -> SimError {
+		return SimError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for SimError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Sim failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for SimError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+/// The power switch of a simulated board, which is nothing more than a file
+#[derive(Debug)]
+pub struct Switch {
+	name: String,
+	dir: PathBuf,
+}
+
+impl Switch {
+	pub fn new(name: &str, sim: &SimConfig) -> Switch
+	{
+		return Switch {
+			name: name.to_string(),
+			dir: path::absolute(&sim.dir).unwrap_or_else(|_| return sim.dir.clone()),
+		}
+	}
+
+	/// where lab sim puts the board's uart
+	pub fn tty(&self) -> PathBuf
+	{
+		return self.dir.join(format!("{}.tty", self.name))
+	}
+
+	fn state(&self) -> PathBuf
+	{
+		return self.dir.join(format!("{}.power", self.name))
+	}
+
+	fn set(&self, on: bool) -> Result<(), Box<dyn std::error::Error>>
+	{
+		let state = match on {
+			true => "on",
+			false => "off",
+		};
+
+		fs::create_dir_all(&self.dir)
+			.and_then(|_| return fs::write(self.state(), format!("{}\n", state)))
+			.map_err(|e| return SimError::new(&format!("{}: {}", self.state().display(), e)))?;
+
+		return Ok(())
+	}
+}
+
+impl PowerBackend for Switch {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.set(true)
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.set(false)
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		match fs::read_to_string(self.state()) {
+			Ok(state) => return Ok(state.trim() == "on"),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+			Err(e) => {
+				return Err(Box::new(SimError::new(&format!("{}: {}", self.state().display(), e))))
+			},
+		}
+	}
+
+	/// Only "attached" while lab sim is there to play the board. Simulated
+	/// boards have no serial, hence "n/a".
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		match self.tty().exists() {
+			true => return Ok(vec!["n/a".to_string()]),
+			false => return Ok(Vec::new()),
+		}
+	}
+
+	fn hub(&self) -> String
+	{
+		return format!("sim:{}", self.name)
+	}
+
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		let on = self.status()?;
+
+		return Ok(ports.iter().map(|_| return on).collect())
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		match action {
+			Action::On => return format!("write \"on\" to {}", self.state().display()),
+			Action::Off => return format!("write \"off\" to {}", self.state().display()),
+			Action::Status => return format!("read {}", self.state().display()),
+			Action::List => return format!("look for {}", self.tty().display()),
+		}
+	}
+}
+
+/// a line of boot log, and how long after the one before it to print it
+#[derive(Clone, Debug)]
+struct Entry {
+	after: Duration,
+	text: String,
+}
+
+/// Plain logs get a line every delay. Transcripts lab wrote go at the pace
+/// they were recorded at, sped up, with lab's own markers left out.
+fn parse_log(log: &str, delay: Duration, speed: f64) -> Vec<Entry>
+{
+	let stamped = Regex::new(r"^\S+ \[\s*(\+[0-9.]+|-[0-9.]*)\] (.*)$").unwrap();
+	let mut lines = log.lines();
+	let transcript = log.starts_with("# ") && log.lines().nth(1).is_some_and(|line| {
+		return stamped.is_match(line)
+	});
+	let mut entries = Vec::new();
+	let mut last = 0.0;
+
+	if transcript {
+		lines.next();
+	}
+
+	for line in lines {
+		let line = line.trim_end_matches('\r');
+		let captures = match stamped.captures(line).filter(|_| return transcript) {
+			Some(captures) => captures,
+			None => {
+				entries.push(Entry{after: delay, text: line.to_string()});
+				continue;
+			},
+		};
+
+		let text = &captures[2];
+		if text.starts_with("[lab: ") {
+			continue;
+		}
+
+		let after = match captures[1].strip_prefix('+').and_then(|at| return at.parse::<f64>().ok()) {
+			Some(at) => {
+				let after = Duration::from_secs_f64(((at - last) / speed).max(0.0));
+				last = at;
+				after
+			},
+			None => delay,
+		};
+		entries.push(Entry{after, text: text.to_string()});
+	}
+
+	return entries
+}
+
+struct Pty {
+	master: fs::File,
+	/* held open so the master doesn't see a hangup each time lab lets go */
+	_slave: fs::File,
+	path: PathBuf,
+}
+
+fn open_pty() -> io::Result<Pty>
+{
+	let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+	if fd < 0 {
+		return Err(io::Error::last_os_error())
+	}
+	let master = unsafe { fs::File::from_raw_fd(fd) };
+
+	if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+		return Err(io::Error::last_os_error())
+	}
+
+	let mut name = [0 as libc::c_char; 64];
+	let failed = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+	if failed != 0 {
+		return Err(io::Error::from_raw_os_error(failed))
+	}
+	let path = PathBuf::from(unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned());
+
+	let slave = fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.custom_flags(libc::O_NOCTTY)
+		.open(&path)?;
+
+	/* raw, so nothing gets echoed or has its newlines mangled on the way */
+	let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+	if unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) } != 0 {
+		return Err(io::Error::last_os_error())
+	}
+	unsafe { libc::cfmakeraw(&mut termios) };
+	if unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) } != 0 {
+		return Err(io::Error::last_os_error())
+	}
+
+	return Ok(Pty {
+		master,
+		_slave: slave,
+		path,
+	})
+}
+
+/// how a boot came to an end
+enum Ending {
+	PowerCut,
+	/// powered but doing nothing, be it halted, panicked or hung
+	Stopped,
+	Rebooted,
+}
+
+/// One simulated board being played back
+struct Player {
+	name: String,
+	sim: SimConfig,
+	switch: Switch,
+	entries: Vec<Entry>,
+	user: String,
+	/// anything goes without one
+	password: Option<String>,
+	pty: Pty,
+	garbled: bool,
+	seed: u64,
+}
+
+impl Player {
+	fn delay(&self) -> Duration
+	{
+		return Duration::from_millis(self.sim.delay)
+	}
+
+	/// bytes flipped here & there, like a uart at the wrong baud rate
+	fn garble(&mut self, data: &[u8]) -> Vec<u8>
+	{
+		return data.iter().map(|byte| {
+			/* xorshift, anything more would be wasted on this */
+			self.seed ^= self.seed << 13;
+			self.seed ^= self.seed >> 7;
+			self.seed ^= self.seed << 17;
+			match self.seed % 8 {
+				0 => return byte ^ (0x80 | (self.seed >> 8) as u8 & 0x7f),
+				_ => return *byte,
+			}
+		}).collect()
+	}
+
+	fn say(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>>
+	{
+		let data = match self.garbled {
+			true => self.garble(text.as_bytes()),
+			false => text.as_bytes().to_vec(),
+		};
+		self.pty.master.write_all(&data)?;
+
+		return Ok(())
+	}
+
+	fn say_line(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.say(&format!("{}\r\n", text))
+	}
+
+	/// Whatever gets typed within timeout, or None should the power go off
+	fn listen(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>
+	{
+		if !self.switch.status()? {
+			return Ok(None)
+		}
+
+		let mut poll = libc::pollfd {
+			fd: self.pty.master.as_raw_fd(),
+			events: libc::POLLIN,
+			revents: 0,
+		};
+		let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+		if ready < 0 {
+			return Err(Box::new(io::Error::last_os_error()))
+		}
+
+		let mut data = [0u8; 256];
+		match ready {
+			0 => return Ok(Some(Vec::new())),
+			_ => {
+				let read = self.pty.master.read(&mut data)?;
+				return Ok(Some(data[..read].to_vec()))
+			},
+		}
+	}
+
+	/// Ignore anything typed for a while, false should the power go off
+	fn pause(&mut self, duration: Duration) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		let until = Instant::now() + duration;
+
+		loop {
+			let left = until.saturating_duration_since(Instant::now());
+			if self.listen(left.min(POLL_INTERVAL))?.is_none() {
+				return Ok(false)
+			}
+			if left.is_zero() {
+				return Ok(true)
+			}
+		}
+	}
+
+	/// A line typed in, echoed back as it goes if echo, or None should the
+	/// power go off
+	fn read_line(&mut self, echo: bool) -> Result<Option<String>, Box<dyn std::error::Error>>
+	{
+		let mut line = Vec::new();
+
+		loop {
+			let typed = match self.listen(POLL_INTERVAL)? {
+				Some(typed) => typed,
+				None => return Ok(None),
+			};
+
+			for byte in typed {
+				if byte == b'\r' || byte == b'\n' {
+					self.say("\r\n")?;
+					return Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+				}
+				if echo {
+					self.say(&String::from_utf8_lossy(&[byte]))?;
+				}
+				line.push(byte);
+			}
+		}
+	}
+
+	fn shell(&mut self) -> Result<Ending, Box<dyn std::error::Error>>
+	{
+		let prompt = format!("[{}@{} ~]# ", self.user, self.name);
+
+		loop {
+			self.say(&prompt)?;
+			let command = match self.read_line(true)? {
+				Some(command) => command,
+				None => return Ok(Ending::PowerCut),
+			};
+
+			match command.split_whitespace().next() {
+				None => (),
+				Some("poweroff") => {
+					if !self.pause(self.delay())? {
+						return Ok(Ending::PowerCut)
+					}
+					self.say_line("reboot: System halted")?;
+					return Ok(Ending::Stopped)
+				},
+				Some("reboot") => {
+					self.say_line("reboot: Restarting system")?;
+					return Ok(Ending::Rebooted)
+				},
+				Some(other) => self.say_line(&format!("-bash: {}: command not found", other))?,
+			}
+		}
+	}
+
+	/// the login prompt has been shown, so wait for someone to log in
+	fn login(&mut self) -> Result<Ending, Box<dyn std::error::Error>>
+	{
+		loop {
+			let user = match self.read_line(true)? {
+				Some(user) => user,
+				None => return Ok(Ending::PowerCut),
+			};
+			self.say("Password: ")?;
+			let password = match self.read_line(false)? {
+				Some(password) => password,
+				None => return Ok(Ending::PowerCut),
+			};
+
+			let right = self.password.as_ref().is_none_or(|expected| return *expected == password);
+			if user == self.user && right {
+				debug!("{}: {} logged in", self.name, user);
+				return self.shell()
+			}
+
+			if !self.pause(LOGIN_DELAY)? {
+				return Ok(Ending::PowerCut)
+			}
+			self.say(&format!("\r\nLogin incorrect\r\n{} login: ", self.name))?;
+		}
+	}
+
+	/// play the boot log back, up to the login prompt if it has one
+	fn boot(&mut self) -> Result<Ending, Box<dyn std::error::Error>>
+	{
+		let entries = self.entries.clone();
+		let fault = self.sim.fault.clone();
+		let fault_at = match &fault {
+			None => None,
+			Some(fault) => match &fault.at {
+				None => Some(0),
+				Some(at) => {
+					let at = Regex::new(at)?;
+					entries.iter().position(|entry| return at.is_match(&entry.text))
+				},
+			},
+		};
+		self.garbled = false;
+
+		for (i, entry) in entries.iter().enumerate() {
+			if !self.pause(entry.after)? {
+				return Ok(Ending::PowerCut)
+			}
+
+			if let (Some(fault), Some(at)) = (&fault, fault_at) {
+				if i == at {
+					debug!("{}: {:?} at \"{}\"", self.name, fault.kind, entry.text);
+				}
+				match fault.kind {
+					Fault::Panic if i == at => {
+						for line in PANIC {
+							self.say_line(line)?;
+						}
+						return Ok(Ending::Stopped)
+					},
+					Fault::Hang if i == at => return Ok(Ending::Stopped),
+					Fault::Garble if i == at => self.garbled = true,
+					_ => (),
+				}
+			}
+
+			if let Some(prompt) = entry.text.find("login: ") {
+				self.say(&entry.text[..prompt + "login: ".len()])?;
+				return self.login()
+			}
+			self.say_line(&entry.text)?;
+		}
+
+		self.say(&format!("\r\n{} login: ", self.name))?;
+		return self.login()
+	}
+
+	fn play(&mut self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		loop {
+			while !self.switch.status()? {
+				/* a board that is off has nothing to say to whatever is typed */
+				self.listen(POLL_INTERVAL)?;
+				thread::sleep(POLL_INTERVAL);
+			}
+			debug!("{}: powered on", self.name);
+
+			loop {
+				match self.boot()? {
+					Ending::Rebooted => continue,
+					Ending::Stopped => while self.pause(POLL_INTERVAL)? {},
+					Ending::PowerCut => (),
+				}
+				break;
+			}
+			debug!("{}: powered off", self.name);
+		}
+	}
+}
+
+fn player(name: &str, board: &config::BoardConfig, config: &config::Config)
+-> Result<Player, Box<dyn std::error::Error>>
+{
+	let sim = config.sim(board).ok_or_else(|| {
+		return ConfigParsingError::new(&format!("{} is not a sim board", name))
+	})?;
+	let switch = Switch::new(name, &sim);
+
+	let log = match &sim.log {
+		Some(log) => fs::read_to_string(log)
+			.map_err(|e| return SimError::new(&format!("{}: {}", log.display(), e)))?,
+		None => DEFAULT_LOG.replace("{board}", name),
+	};
+	let entries = parse_log(&log, Duration::from_millis(sim.delay), sim.speed);
+
+	let credentials = config.credentials(board)
+		.map_err(|e| return ConfigParsingError::new(&format!("{}: {}", name, e)))?;
+	let password = match credentials.password() {
+		Ok(password) => Some(password.expose().to_string()),
+		Err(e) => {
+			debug!("{}: {}, so any password will do", name, e);
+			None
+		},
+	};
+
+	fs::create_dir_all(&switch.dir)
+		.map_err(|e| return SimError::new(&format!("{}: {}", switch.dir.display(), e)))?;
+	let pty = open_pty().map_err(|e| return SimError::new(&format!("{}: pty: {}", name, e)))?;
+	let tty = switch.tty();
+	let _ = fs::remove_file(&tty);
+	symlink(&pty.path, &tty)
+		.map_err(|e| return SimError::new(&format!("{}: {}", tty.display(), e)))?;
+	println!("{}: {} on {}", name, tty.display(), pty.path.display());
+
+	let seed = SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map(|now| return now.as_nanos() as u64)
+		.unwrap_or(1) | 1;
+
+	return Ok(Player {
+		name: name.to_string(),
+		user: credentials.user.clone(),
+		password,
+		sim,
+		switch,
+		entries,
+		pty,
+		garbled: false,
+		seed,
+	})
+}
+
+/// Play simulated boards back, all of them if none are given, until
+/// interrupted
+pub fn serve(board_names: &[String], input_file: String) -> Result<(), Box<dyn std::error::Error>>
+{
+	let config = config::load(&input_file)?;
+	let mut players = Vec::new();
+
+	/* only for the names, and complaining about any that don't exist */
+	for board in boards::get_boards_from_config(board_names, input_file)? {
+		let (_, board_config) = config.boards
+			.iter()
+			.find(|(name, _)| return *name == board.name)
+			.unwrap();
+
+		if board_names.is_empty() && board_config.sim.is_none() {
+			continue;
+		}
+		players.push(player(&board.name, board_config, &config)?);
+	}
+
+	if players.is_empty() {
+		return Err(Box::new(SimError::new("no sim boards in the config")))
+	}
+
+	thread::scope(|scope| {
+		for mut player in players {
+			scope.spawn(move || {
+				if let Err(e) = player.play() {
+					error!("{}: {}", player.name, e);
+				}
+			});
+		}
+	});
+
+	return Err(Box::new(SimError::new("every board has stopped")))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::boards::{Board, Ops};
+	use crate::console;
+
+	/// A sim board with whatever fault, already being played back on a
+	/// thread of its own. Hands back the board & the directory it is in.
+	fn played(test: &str, fault: &str) -> (Board, PathBuf)
+	{
+		let dir = std::env::temp_dir().join(format!("lab-sim-{}-{}", test, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("config.yaml").display().to_string();
+		fs::write(&path, format!(r#"
+boards:
+  board:
+    type: sim
+    login: {{user: root, password: hunter2}}
+    boot:
+      steps:
+        - {{name: U-Boot, expect: "U-Boot", timeout: 5}}
+        - {{name: Linux, expect: "Linux version", retry: "Hit any key", timeout: 2}}
+        - {{name: login, expect: "login: ", send: "{{user}}", timeout: 5}}
+        - {{name: password, expect: "Password: ", send: "{{password}}", timeout: 5}}
+        - {{name: shell, expect: "\\]# ", timeout: 5}}
+    sim:
+      delay: 5
+      dir: run
+      {}
+"#, fault)).unwrap();
+
+		let config = config::load(&path).unwrap();
+		let (_, board_config) = config.boards.iter().find(|(name, _)| return name == "board").unwrap();
+		let mut player = player("board", board_config, &config).unwrap();
+		/* garbled the same way every time */
+		player.seed = 1;
+		thread::spawn(move || {
+			if let Err(e) = player.play() {
+				error!("{}: {}", player.name, e);
+			}
+		});
+
+		let board = boards::get_boards_from_config(&["board".to_string()], path).unwrap().remove(0);
+		return (board, dir)
+	}
+
+	/// the board booted & shut down again as a boot test would, with how
+	/// that went & everything seen along the way
+	fn boot(board: &Board) -> (Result<(), String>, String)
+	{
+		let console = console::attach(&board.primary_uart).unwrap();
+		let mut session = console.session(Duration::from_secs(5));
+		let mut output = Vec::new();
+
+		board.power_on().unwrap();
+		let result = board.expect_boot(&mut session, &mut output, &mut Vec::new())
+			.and_then(|_| return board.expect_shutdown(&mut session, &mut output))
+			.map_err(|e| return e.to_string());
+		board.power_off().unwrap();
+
+		return (result, output.concat())
+	}
+
+	#[test]
+	fn a_good_board_logs_in_and_powers_off()
+	{
+		let (board, dir) = played("good", "");
+		let (result, output) = boot(&board);
+
+		assert!(result.is_ok(), "{:?}\n{}", result, output);
+		assert!(output.contains("Welcome to Fedora Linux!"), "{}", output);
+		assert!(output.contains("[root@board ~]# "), "{}", output);
+		assert!(output.contains("# poweroff\r\nreboot: System halted"), "{}", output);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn a_panic_fails_the_boot_straight_away()
+	{
+		let (board, dir) = played("panic", r#"fault: {kind: panic, at: "Run /sbin/init"}"#);
+		let started = Instant::now();
+		let (result, output) = boot(&board);
+
+		let error = result.unwrap_err();
+		assert!(error.contains(r#"login: saw "Kernel panic - not syncing: Attempted to kill init!"#), "{}", error);
+		assert!(!output.contains("Run /sbin/init"), "{}", output);
+		assert!(started.elapsed() < Duration::from_secs(4));
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn a_hang_times_out()
+	{
+		let (board, dir) = played("hang", r#"fault: {kind: hang, at: "Starting kernel"}"#);
+		let (result, output) = boot(&board);
+
+		assert_eq!(result.unwrap_err(), "Boot test failed: Linux: nothing after 2s");
+		assert!(output.contains("Hit any key to stop autoboot:  0"), "{}", output);
+		assert!(!output.contains("Starting kernel"), "{}", output);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn garbled_output_gets_nowhere()
+	{
+		let (board, dir) = played("garble", "fault: {kind: garble}");
+		let (result, output) = boot(&board);
+
+		let error = result.unwrap_err();
+		assert!(error.contains("nothing after"), "{}", error);
+		assert!(output.contains('\u{fffd}'), "{}", output);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+}
@@ -0,0 +1,448 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{fmt, time::Duration};
+use log::debug;
+use crate::config::{Secret, SnmpConfig, SnmpVersion};
+use crate::power::{self, Action, PowerBackend};
+
+/*
+ * Outlets of a switched PDU, by way of net-snmp's snmpget & snmpset. The
+ * community & keys have to go on their command lines, but never into anything
+ * lab prints, be that an error or what --dry-run would do.
+ */
+const HIDDEN: &str = "********";
+/* a retry on top of the first go, each getting the whole timeout */
+const RETRIES: u64 = 1;
+const SYS_DESCR: &str = ".1.3.6.1.2.1.1.1.0";
+
+#[derive(Debug)]
+pub struct SnmpError {
+	details: String
+}
+
+impl SnmpError {
+	pub fn new(msg: &str) -> SnmpError {
+		return SnmpError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for SnmpError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "SNMP failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for SnmpError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+#[derive(Debug)]
+pub struct Snmp {
+	config: SnmpConfig,
+	snmpget: String,
+	snmpset: String,
+}
+
+impl Snmp {
+	pub fn new(config: &SnmpConfig) -> Snmp {
+		return Snmp {
+			config: config.clone(),
+			snmpget: "snmpget".to_string(),
+			snmpset: "snmpset".to_string(),
+		}
+	}
+
+	/// A secret as it goes on the command line, noting it down to be kept
+	/// out of anything printed. Unless revealed, it isn't even looked up.
+	fn secret(reveal: bool, secrets: &mut Vec<Secret>,
+		  look_up: impl FnOnce() -> Result<Secret, Box<dyn std::error::Error>>)
+	-> Result<String, Box<dyn std::error::Error>>
+	{
+		if !reveal {
+			return Ok(HIDDEN.to_string())
+		}
+
+		let secret = look_up()?;
+		let exposed = secret.expose().to_string();
+		secrets.push(secret);
+
+		return Ok(exposed)
+	}
+
+	/// how much v3 goes in for, by which keys it has
+	fn level(&self) -> &'static str
+	{
+		match (&self.config.auth, &self.config.privacy) {
+			(Some(_), Some(_)) => return "authPriv",
+			(Some(_), None) => return "authNoPriv",
+			(None, _) => return "noAuthNoPriv",
+		}
+	}
+
+	/// the arguments saying who lab is to the PDU, and what they give away
+	fn security(&self, reveal: bool) -> Result<(Vec<String>, Vec<Secret>), Box<dyn std::error::Error>>
+	{
+		let mut secrets = Vec::new();
+
+		let args = match self.config.version {
+			SnmpVersion::V2c => {
+				let community = Snmp::secret(reveal, &mut secrets, || return self.config.community())?;
+				vec!["-v2c".to_string(), "-c".to_string(), community]
+			},
+			SnmpVersion::V3 => {
+				let mut args = vec![
+					"-v3".to_string(),
+					"-l".to_string(), self.level().to_string(),
+					"-u".to_string(), self.config.user.clone().unwrap_or_default(),
+				];
+				if let Some(auth) = &self.config.auth {
+					let key = Snmp::secret(reveal, &mut secrets, || return self.config.key(auth))?;
+					args.extend(["-a".to_string(), auth.protocol.clone(), "-A".to_string(), key]);
+				}
+				if let Some(privacy) = &self.config.privacy {
+					let key = Snmp::secret(reveal, &mut secrets, || return self.config.key(privacy))?;
+					args.extend(["-x".to_string(), privacy.protocol.clone(), "-X".to_string(), key]);
+				}
+				args
+			},
+		};
+
+		return Ok((args, secrets))
+	}
+
+	/// the whole of a snmpget or snmpset, asking for bare numbers back
+	fn argv(&self, tool: &str, args: &[String], reveal: bool)
+	-> Result<(Vec<String>, Vec<Secret>), Box<dyn std::error::Error>>
+	{
+		let (security, secrets) = self.security(reveal)?;
+		let mut argv = vec![tool.to_string()];
+
+		argv.extend(security);
+		argv.extend([
+			"-t".to_string(), self.config.timeout.to_string(),
+			"-r".to_string(), RETRIES.to_string(),
+			"-Oeqv".to_string(),
+			self.config.host.clone(),
+		]);
+		argv.extend_from_slice(args);
+
+		return Ok((argv, secrets))
+	}
+
+	fn run(&self, tool: &str, args: &[String]) -> Result<String, Box<dyn std::error::Error>>
+	{
+		let (argv, secrets) = self.argv(tool, args, true)?;
+		let hide = |text: String| {
+			return secrets
+				.iter()
+				.filter(|secret| return !secret.expose().is_empty())
+				.fold(text, |text, secret| return text.replace(secret.expose(), HIDDEN))
+		};
+		/* the tool gives up by itself first, this is for it getting stuck */
+		let timeout = Duration::from_secs(self.config.timeout * (RETRIES + 1) + 5);
+
+		let output = power::execute(&argv, timeout).map_err(|e| return SnmpError::new(&hide(e)))?;
+		if !output.status.success() {
+			return Err(Box::new(SnmpError::new(&hide(power::failure(&argv, &output)))));
+		}
+
+		return Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+	}
+
+	fn control(&self, value: i64) -> Vec<String>
+	{
+		return vec![
+			format!("{}.{}", self.config.oids.control, self.config.outlet),
+			"i".to_string(),
+			value.to_string(),
+		]
+	}
+
+	fn set(&self, value: i64) -> Result<(), Box<dyn std::error::Error>>
+	{
+		self.run(&self.snmpset, &self.control(value))?;
+		debug!("{} outlet {} set to {}", self.config.host, self.config.outlet, value);
+
+		return Ok(())
+	}
+
+	fn explain_set(&self, value: i64) -> String
+	{
+		match self.argv(&self.snmpset, &self.control(value), false) {
+			Ok((argv, _)) => return argv.join(" "),
+			Err(e) => return e.to_string(),
+		}
+	}
+}
+
+impl PowerBackend for Snmp {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.set(self.config.oids.on)
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return self.set(self.config.oids.off)
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		return Ok(self.port_states(&[self.config.outlet.to_string()])?[0])
+	}
+
+	/// Only this board's PDU can be asked after, and is there if it answers
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		self.run(&self.snmpget, &[SYS_DESCR.to_string()])?;
+
+		return Ok(vec![self.config.host.clone()])
+	}
+
+	/// The PDU, and how it gets read. Boards on one PDU are only read
+	/// together when that's all the same, what with port_states() reading
+	/// the lot the way this board would. The secrets are left to config
+	/// check, so as not to turn up in logs.
+	fn hub(&self) -> String
+	{
+		let security = match self.config.version {
+			SnmpVersion::V2c => "v2c".to_string(),
+			SnmpVersion::V3 => format!("v3 {} {}", self.config.user.as_deref().unwrap_or("-"),
+						   self.level()),
+		};
+
+		return format!("snmp:{} {} {}={}", self.config.host, security, self.config.oids.state,
+			       self.config.oids.powered)
+	}
+
+	/// every outlet asked after in the one request
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		let oids: Vec<String> = ports
+			.iter()
+			.map(|port| return format!("{}.{}", self.config.oids.state, port))
+			.collect();
+		let answer = self.run(&self.snmpget, &oids)?;
+		let values: Vec<&str> = answer.lines().map(|line| return line.trim()).collect();
+
+		if values.len() != ports.len() {
+			return Err(Box::new(SnmpError::new(&format!(
+				"{} answered {} value(s) for {} outlet(s)",
+				self.config.host, values.len(), ports.len()))))
+		}
+
+		/* an outlet it has never heard of comes back as a sentence instead */
+		return ports
+			.iter()
+			.zip(values)
+			.map(|(port, value)| {
+				let value: i64 = value.parse().map_err(|_| {
+					return SnmpError::new(&format!("{} outlet {}: {}", self.config.host, port, value))
+				})?;
+				return Ok(value == self.config.oids.powered)
+			})
+			.collect()
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		let get = |oid: String| {
+			match self.argv(&self.snmpget, &[oid], false) {
+				Ok((argv, _)) => return argv.join(" "),
+				Err(e) => return e.to_string(),
+			}
+		};
+
+		match action {
+			Action::On => return self.explain_set(self.config.oids.on),
+			Action::Off => return self.explain_set(self.config.oids.off),
+			Action::Status => {
+				return get(format!("{}.{}", self.config.oids.state, self.config.outlet))
+			},
+			Action::List => return get(SYS_DESCR.to_string()),
+		}
+	}
+
+	/// in one go, if the PDU knows how
+	fn reboot(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		match self.config.oids.reboot {
+			Some(reboot) => return self.set(reboot),
+			None => return power::power_cycle(self),
+		}
+	}
+
+	fn explain_reboot(&self) -> String
+	{
+		match self.config.oids.reboot {
+			Some(reboot) => return self.explain_set(reboot),
+			None => return power::explain_power_cycle(self),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{fs, os::unix::fs::PermissionsExt, path::{Path, PathBuf}};
+
+	/*
+	 * Stands in for snmpget & snmpset against a PDU with outlets 1 & 3 on, 2
+	 * off and no outlet 9. A host called "dead" never answers, like a PDU
+	 * given the wrong community.
+	 */
+	const STUB: &str = r#"#!/bin/sh
+printf '%s\n' "$*" >> "${0%/*}/calls"
+for arg; do
+	case "$arg" in
+	dead) echo "Timeout: No Response from $*" >&2; exit 1;;
+	*.1.1.4.1|*.1.1.4.3) echo 1;;
+	*.1.1.4.2) echo 2;;
+	*.1.1.4.9) echo "No Such Instance currently exists at this OID";;
+	esac
+done
+"#;
+
+	fn stub(test: &str) -> PathBuf
+	{
+		let dir = std::env::temp_dir().join(format!("lab-snmp-{}-{}", test, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+
+		let tool = dir.join("snmp");
+		fs::write(&tool, STUB).unwrap();
+		fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+		return dir
+	}
+
+	fn pdu(dir: &Path, config: &str) -> Snmp
+	{
+		let tool = dir.join("snmp").display().to_string();
+
+		return Snmp {
+			config: serde_yaml::from_str(config).unwrap(),
+			snmpget: tool.clone(),
+			snmpset: tool,
+		}
+	}
+
+	fn calls(dir: &Path) -> Vec<String>
+	{
+		return fs::read_to_string(dir.join("calls"))
+			.unwrap_or_default()
+			.lines()
+			.map(|line| return line.to_string())
+			.collect()
+	}
+
+	const V2C: &str = "{host: localhost:1161, outlet: 1, community: s3cret}";
+	const V3: &str = "{host: dead, outlet: 1, version: \"3\", user: lab, \
+			  auth: {protocol: SHA, password: authkey1}, \
+			  privacy: {protocol: AES, password: privkey1}}";
+
+	#[test]
+	fn outlets_are_read_together_in_order()
+	{
+		let dir = stub("batch");
+		let states = pdu(&dir, V2C).port_states(&["3".to_string(), "2".to_string(), "1".to_string()]);
+
+		assert_eq!(states.unwrap(), vec![true, false, true]);
+
+		let calls = calls(&dir);
+		assert_eq!(calls.len(), 1);
+		assert!(calls[0].ends_with(" .1.3.6.1.4.1.318.1.1.12.3.5.1.1.4.3 \
+					   .1.3.6.1.4.1.318.1.1.12.3.5.1.1.4.2 \
+					   .1.3.6.1.4.1.318.1.1.12.3.5.1.1.4.1"), "{}", calls[0]);
+	}
+
+	#[test]
+	fn missing_outlets_are_errors()
+	{
+		let dir = stub("nosuch");
+		let error = pdu(&dir, V2C).port_states(&["1".to_string(), "9".to_string()]).unwrap_err();
+
+		assert!(error.is::<SnmpError>());
+		assert!(error.to_string().contains("outlet 9: No Such Instance"));
+	}
+
+	#[test]
+	fn short_answers_are_errors()
+	{
+		let dir = stub("short");
+		/* outlet 5 gets nothing at all back */
+		let error = pdu(&dir, V2C).port_states(&["1".to_string(), "5".to_string()]).unwrap_err();
+
+		assert!(error.to_string().contains("answered 1 value(s) for 2 outlet(s)"));
+	}
+
+	#[test]
+	fn switching_sets_the_control()
+	{
+		let dir = stub("switch");
+		let pdu = pdu(&dir, V2C);
+
+		pdu.on().unwrap();
+		pdu.off().unwrap();
+		pdu.reboot().unwrap();
+
+		let calls = calls(&dir);
+		assert_eq!(calls.len(), 3);
+		for (call, value) in calls.iter().zip(["1", "2", "3"]) {
+			assert!(call.ends_with(&format!("localhost:1161 .1.3.6.1.4.1.318.1.1.12.3.3.1.1.4.1 i {}",
+							value)), "{}", call);
+		}
+	}
+
+	#[test]
+	fn secrets_stay_out_of_explanations()
+	{
+		let dir = stub("explain");
+
+		for (config, secrets) in [(V2C, vec!["s3cret"]), (V3, vec!["authkey1", "privkey1"])] {
+			let pdu = pdu(&dir, config);
+			for action in [Action::On, Action::Off, Action::Status, Action::List] {
+				let explained = pdu.explain(action);
+				assert!(explained.contains(HIDDEN), "{}", explained);
+				assert!(secrets.iter().all(|secret| return !explained.contains(secret)), "{}", explained);
+			}
+			assert!(secrets.iter().all(|secret| return !pdu.explain_reboot().contains(secret)));
+		}
+		assert!(calls(&dir).is_empty());
+	}
+
+	#[test]
+	fn secrets_stay_out_of_errors()
+	{
+		let dir = stub("errors");
+		let error = pdu(&dir, V3).on().unwrap_err().to_string();
+
+		/* the stub says the lot back, as a tool failing can */
+		assert!(error.contains("Timeout: No Response"), "{}", error);
+		assert!(!error.contains("authkey1") && !error.contains("privkey1"), "{}", error);
+		assert!(calls(&dir)[0].contains("-A authkey1"));
+	}
+
+	#[test]
+	fn hubs_differ_with_how_they_are_read()
+	{
+		let dir = stub("hub");
+		let apc = pdu(&dir, V2C);
+		let other_outlet = pdu(&dir, "{host: localhost:1161, outlet: 2, community: s3cret}");
+		let raritan = pdu(&dir, "{host: localhost:1161, outlet: 2, community: s3cret, \
+					  oids: {control: .1.2, state: .1.3, on: 1, off: 0, powered: 7}}");
+
+		let without_privacy = pdu(&dir, "{host: dead, outlet: 2, version: \"3\", user: lab, \
+						  auth: {protocol: SHA, password: authkey1}}");
+
+		assert_eq!(apc.hub(), other_outlet.hub());
+		assert_ne!(apc.hub(), raritan.hub());
+		assert_ne!(pdu(&dir, V3).hub(), without_privacy.hub());
+		assert!(!apc.hub().contains("s3cret"));
+	}
+}
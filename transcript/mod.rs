@@ -0,0 +1,403 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use chrono::{DateTime, Local};
+use std::{
+	fmt, fs,
+	io::{BufWriter, Write},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, mpsc, atomic::{AtomicBool, Ordering}},
+	thread,
+	time::{Duration, SystemTime},
+};
+use log::{debug, warn};
+
+use crate::boards::Board;
+use crate::console::{Chunk, Console, Stamped};
+
+/* how long written lines might sit in the buffer before hitting the disk */
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub struct TranscriptError {
+	details: String
+}
+
+impl TranscriptError {
+	pub fn new(msg: &str) -> TranscriptError {
+		return TranscriptError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for TranscriptError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "Transcript failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for TranscriptError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+/// Everything a board's console prints during a run, byte for byte bar any
+/// secrets, written out as it arrives with the time of day and the time
+/// since power went on.
+pub struct Transcript {
+	pub path: PathBuf,
+	keep: usize,
+	console: Arc<Console>,
+	done: Arc<AtomicBool>,
+	writer: thread::JoinHandle<()>,
+}
+
+/// the time of day & how long since power went on, that every line of a
+/// transcript starts with
+fn stamp(at: SystemTime, powered_on: Option<SystemTime>) -> String
+{
+	let since = match powered_on {
+		None => "-".to_string(),
+		Some(on) => match at.duration_since(on) {
+			Ok(since) => format!("+{:.3}", since.as_secs_f64()),
+			Err(before) => format!("-{:.3}", before.duration().as_secs_f64()),
+		},
+	};
+	let at: DateTime<Local> = at.into();
+
+	return format!("{} [{:>10}] ", at.format("%Y-%m-%dT%H:%M:%S%.3f%:z"), since)
+}
+
+/// Output gets split into lines as it comes in, each stamped with when it
+/// started and otherwise left exactly as the board printed it. Markers go
+/// on lines of their own.
+struct Lines<W: Write> {
+	file: W,
+	powered_on: Arc<Mutex<Option<SystemTime>>>,
+	/* hang on to it, the last few lines get here after power is cut again */
+	on: Option<SystemTime>,
+	/// the line so far, and when it started
+	partial: Option<(SystemTime, Vec<u8>)>,
+}
+
+impl<W: Write> Lines<W> {
+	fn stamp(&mut self, at: SystemTime) -> String
+	{
+		if let Some(on) = *self.powered_on.lock().unwrap() {
+			self.on = Some(on);
+		}
+
+		return stamp(at, self.on)
+	}
+
+	fn write(&mut self, stamped: Stamped) -> Result<(), Box<dyn std::error::Error>>
+	{
+		match stamped.chunk {
+			Chunk::Output(data) => {
+				for piece in data.split_inclusive(|byte| return *byte == b'\n') {
+					let (_, line) = self.partial.get_or_insert_with(|| return (stamped.at, Vec::new()));
+					line.extend_from_slice(piece);
+
+					if piece.ends_with(b"\n") {
+						self.end_line()?;
+					}
+				}
+			},
+			Chunk::Marker(text) => {
+				self.end_line()?;
+				let stamp = self.stamp(stamped.at);
+				writeln!(self.file, "{}[lab: {}]", stamp, text)?;
+			},
+		}
+
+		return Ok(())
+	}
+
+	/// out with the line so far, whether or not the board got to the end of it
+	fn end_line(&mut self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		if let Some((at, line)) = self.partial.take() {
+			let stamp = self.stamp(at);
+			self.file.write_all(stamp.as_bytes())?;
+			self.file.write_all(&line)?;
+			if !line.ends_with(b"\n") {
+				self.file.write_all(b"\n")?;
+			}
+		}
+
+		return Ok(())
+	}
+}
+
+fn write_lines(file: BufWriter<fs::File>, chunks: mpsc::Receiver<Stamped>,
+	       powered_on: Arc<Mutex<Option<SystemTime>>>, done: Arc<AtomicBool>)
+-> Result<(), Box<dyn std::error::Error>>
+{
+	let mut lines = Lines {
+		file,
+		powered_on,
+		on: None,
+		partial: None,
+	};
+
+	loop {
+		if done.load(Ordering::SeqCst) {
+			for stamped in chunks.try_iter() {
+				lines.write(stamped)?;
+			}
+			break;
+		}
+
+		match chunks.recv_timeout(FLUSH_INTERVAL) {
+			Ok(stamped) => lines.write(stamped)?,
+			Err(mpsc::RecvTimeoutError::Timeout) => lines.file.flush()?,
+			Err(mpsc::RecvTimeoutError::Disconnected) => break,
+		}
+	}
+
+	/* a half printed prompt is often what a run got stuck on */
+	lines.end_line()?;
+	lines.file.flush()?;
+	return Ok(())
+}
+
+/// the oldest runs for a board go once there are more than keep of them
+fn prune(dir: &Path, keep: usize) -> Result<(), Box<dyn std::error::Error>>
+{
+	let mut runs: Vec<PathBuf> = fs::read_dir(dir)?
+		.filter_map(|entry| return entry.ok())
+		.map(|entry| return entry.path())
+		.filter(|path| return path.extension().is_some_and(|ext| return ext == "log"))
+		.collect();
+
+	/* named for when they started, so this is oldest first */
+	runs.sort();
+
+	for run in runs.iter().take(runs.len().saturating_sub(keep)) {
+		debug!("removing old transcript {}", run.display());
+		fs::remove_file(run)?;
+	}
+
+	return Ok(())
+}
+
+/// clear out all but the newest keep runs of a board, unless keep is 0
+pub fn tidy(board: &Board, keep: usize)
+{
+	if keep == 0 {
+		return;
+	}
+
+	let dir = board.transcripts.dir.join(&board.name);
+	if let Err(e) = prune(&dir, keep) {
+		warn!("{}: {}", dir.display(), e);
+	}
+}
+
+impl Transcript {
+	/// start writing out everything from the console from here on, into a
+	/// new file under the board's transcript directory
+	pub fn start(board: &Board, console: &Arc<Console>)
+	-> Result<Transcript, Box<dyn std::error::Error>>
+	{
+		let dir = board.transcripts.dir.join(&board.name);
+		fs::create_dir_all(&dir).map_err(|e| {
+			return TranscriptError::new(&format!("{}: {}", dir.display(), e))
+		})?;
+
+		let path = dir.join(format!("{}.log", Local::now().format("%Y%m%d-%H%M%S%.3f")));
+		let mut file = fs::File::create(&path)
+			.map(BufWriter::new)
+			.map_err(|e| return TranscriptError::new(&format!("{}: {}", path.display(), e)))?;
+		writeln!(file, "# {} on {}", board.name, console.path)?;
+
+		let lines = console.tap();
+		let powered_on = board.powered_on.clone();
+		let done = Arc::new(AtomicBool::new(false));
+		let writer_done = done.clone();
+		let writer_path = path.clone();
+		let writer = thread::spawn(move || {
+			if let Err(e) = write_lines(file, lines, powered_on, writer_done) {
+				warn!("{}: {}", writer_path.display(), e);
+			}
+		});
+
+		debug!("{} transcript in {}", board.name, path.display());
+		return Ok(Transcript {
+			path,
+			keep: board.transcripts.keep,
+			console: console.clone(),
+			done,
+			writer,
+		})
+	}
+
+	/// Write out whatever is still to be written, an unfinished last line
+	/// included, & stop, clearing out old runs unless keep is 0. Hands back where the transcript went.
+	pub fn finish(self) -> PathBuf
+	{
+		self.console.release();
+		self.done.store(true, Ordering::SeqCst);
+		let _ = self.writer.join();
+
+		if self.keep > 0 {
+			if let Some(dir) = self.path.parent() {
+				if let Err(e) = prune(dir, self.keep) {
+					warn!("{}: {}", dir.display(), e);
+				}
+			}
+		}
+
+		return self.path
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use regex::Regex;
+	use std::time::UNIX_EPOCH;
+	use crate::config::TranscriptConfig;
+
+	fn lines() -> Lines<Vec<u8>>
+	{
+		return Lines {
+			file: Vec::new(),
+			powered_on: Arc::new(Mutex::new(None)),
+			on: None,
+			partial: None,
+		}
+	}
+
+	fn output(data: &[u8]) -> Stamped
+	{
+		return Stamped {
+			at: SystemTime::now(),
+			chunk: Chunk::Output(data.to_vec()),
+		}
+	}
+
+	/// what got written, minus the stamps
+	fn written(lines: &Lines<Vec<u8>>) -> Vec<String>
+	{
+		return String::from_utf8_lossy(&lines.file)
+			.split_inclusive('\n')
+			.map(|line| return line.split_once("] ").unwrap().1.to_string())
+			.collect()
+	}
+
+	#[test]
+	fn output_is_written_as_it_came()
+	{
+		let mut lines = lines();
+
+		lines.write(output(b"U-Boot 2023.10\r\n\x1b[1mHit any")).unwrap();
+		lines.write(output(b" key\x1b[0m\r\n")).unwrap();
+
+		assert_eq!(written(&lines), vec!["U-Boot 2023.10\r\n", "\x1b[1mHit any key\x1b[0m\r\n"]);
+	}
+
+	#[test]
+	fn unfinished_lines_are_written_at_the_end()
+	{
+		let mut lines = lines();
+
+		lines.write(output(b"Starting kernel ...\r\n=> ")).unwrap();
+		lines.write(Stamped {
+			at: SystemTime::now(),
+			chunk: Chunk::Marker("Boot test: failed".to_string()),
+		}).unwrap();
+		lines.write(output(b"=> ")).unwrap();
+		lines.end_line().unwrap();
+
+		assert_eq!(written(&lines), vec![
+			"Starting kernel ...\r\n",
+			"=> \n",
+			"[lab: Boot test: failed]\n",
+			"=> \n",
+		]);
+	}
+
+	#[test]
+	fn stamps_have_the_time_of_day_and_since_power_on()
+	{
+		let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+		let time = r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.123[+-]\d{2}:\d{2} ";
+
+		let stamps = [
+			(None, r"\[         -\] $"),
+			(Some(at - Duration::from_millis(1500)), r"\[    \+1\.500\] $"),
+			(Some(at), r"\[    \+0\.000\] $"),
+			/* the board printing before this lab turned it on */
+			(Some(at + Duration::from_millis(250)), r"\[    -0\.250\] $"),
+		];
+
+		for (powered_on, since) in stamps {
+			let stamp = stamp(at, powered_on);
+			assert!(Regex::new(&format!("{}{}", time, since)).unwrap().is_match(&stamp), "{}", stamp);
+		}
+	}
+
+	/// a board with runs transcripts in a directory of their own, & where they are
+	fn runs(test: &str, runs: usize) -> (Board, PathBuf)
+	{
+		let dir = std::env::temp_dir().join(format!("lab-transcript-{}-{}", test, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		let board = Board {
+			name: "board".to_string(),
+			transcripts: TranscriptConfig {
+				dir: dir.clone(),
+				keep: 0,
+			},
+			..Board::default()
+		};
+
+		let runs_dir = dir.join("board");
+		fs::create_dir_all(&runs_dir).unwrap();
+		for run in 0..runs {
+			fs::write(runs_dir.join(format!("20240101-00000{}.000.log", run)), "").unwrap();
+		}
+		fs::write(runs_dir.join("notes.txt"), "").unwrap();
+
+		return (board, dir)
+	}
+
+	fn left(dir: &Path) -> Vec<String>
+	{
+		let mut left: Vec<String> = fs::read_dir(dir.join("board"))
+			.unwrap()
+			.map(|entry| return entry.unwrap().file_name().to_string_lossy().into_owned())
+			.collect();
+		left.sort();
+
+		return left
+	}
+
+	#[test]
+	fn only_the_newest_runs_are_kept()
+	{
+		let (board, dir) = runs("keep", 5);
+
+		tidy(&board, 2);
+		assert_eq!(left(&dir), vec!["20240101-000003.000.log", "20240101-000004.000.log", "notes.txt"]);
+
+		/* fewer than keep, so nothing to do */
+		tidy(&board, 3);
+		assert_eq!(left(&dir).len(), 3);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+
+	#[test]
+	fn keeping_none_keeps_them_all()
+	{
+		let (board, dir) = runs("keep-all", 5);
+
+		tidy(&board, 0);
+		assert_eq!(left(&dir).len(), 6);
+
+		let _ = fs::remove_dir_all(dir);
+	}
+}
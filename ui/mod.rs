@@ -7,19 +7,53 @@ use crossterm::{
 	event::{self, Event, KeyCode},
 	terminal::{disable_raw_mode, enable_raw_mode},
 };
-use std::time::Duration;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::io;
 use tui::{
 	backend::CrosstermBackend,
 	layout::{Constraint, Direction, Layout, Rect},
 	style::{Color, Modifier, Style},
+	text::{Span, Spans, Text},
 	Terminal,
 	widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
 };
-use log::error;
 
 use crate::boards;
+use crate::boottest;
 use crate::boards::{Ops, Status};
+use crate::console::{self, Console, Line};
+use crate::jobs::{Action, Cancel, JobState, Jobs};
+use crate::poller::Poller;
+use crate::power;
+
+/* how often the background poller goes back to the hubs on its own */
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const CONSOLE_PAGE: usize = 10;
+
+#[derive(Debug)]
+pub struct UiError {
+	details: String
+}
+
+impl UiError {
+	pub fn new(msg: &str) -> UiError {
+		return UiError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for UiError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "TUI failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for UiError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
 
 #[derive(Clone)]
 struct StatefulList<T> {
@@ -82,8 +116,6 @@ impl<T> StatefulList<T> {
 	}
 }
 
-type Action = fn(&boards::Board) -> Result<(), Box<dyn std::error::Error>>;
-
 #[derive(Clone)]
 struct UIState<'a> {
 	boards: StatefulList<&'a boards::Board>,
@@ -92,7 +124,8 @@ struct UIState<'a> {
 		(&'a str, Action)
 	>,
 	action_items: List<'a>,
-	text_box: Paragraph<'a>,
+	/// how many lines back from the end the console pane is scrolled
+	console_scroll: usize,
 }
 
 impl<'a> UIState<'a> {
@@ -102,7 +135,7 @@ impl<'a> UIState<'a> {
 			show_popup: false,
 			actions: StatefulList::default(),
 			action_items: List::new(Vec::new()),
-			text_box: Paragraph::new(""),
+			console_scroll: 0,
 		}
 	}
 
@@ -111,57 +144,97 @@ impl<'a> UIState<'a> {
 		return Some(self.boards.items[self.boards.state.selected()?])
 	}
 
-	fn selected_action(self) -> Option<Action>
-	{
-		let selected_action = self.actions.state.selected()?;
-		return Some(self.actions.items[selected_action].1);
-	}
 }
 
-fn power_on(board: &boards::Board)
+fn power_on(board: &boards::Board, _cancel: &Cancel)
 -> Result<(), Box<dyn std::error::Error>>
 {
 	return board.power_on()
 }
 
-fn power_off(board: &boards::Board)
+fn power_off(board: &boards::Board, _cancel: &Cancel)
 -> Result<(), Box<dyn std::error::Error>>
 {
 	return board.power_off()
 }
 
-fn toggle_power_state(board: &boards::Board)
+fn toggle_power_state(board: &boards::Board, cancel: &Cancel)
 -> Result<(), Box<dyn std::error::Error>>
 {
-	if !board.is_powered()? {
+	let powered = board.is_powered()?;
+	cancel.check()?;
+
+	if !powered {
 		return board.power_on()
 	}
 
 	return board.power_off()
 }
 
-fn reboot(board: &boards::Board)
+fn reboot(board: &boards::Board, _cancel: &Cancel)
 -> Result<(), Box<dyn std::error::Error>>
 {
 	return board.reboot()
 }
 
-fn boot_test(board: &boards::Board)
--> Result<(), Box<dyn std::error::Error>>
+fn console_pane(attached: &Option<(String, Result<Arc<Console>, String>)>, scroll: usize,
+		height: usize) -> Paragraph<'static>
 {
-	let mut output = Vec::new();
+	let (title, lines) = match attached {
+		None => ("Console".to_string(), Vec::new()),
+		Some((name, Err(e))) => {
+			(format!("Console: {}", name), vec![Spans::from(Span::raw(e.clone()))])
+		},
+		Some((name, Ok(console))) => {
+			let lines = console.window(height, scroll)
+				.into_iter()
+				.map(|line| {
+					match line {
+						Line::Output(text) => return Spans::from(Span::raw(text)),
+						Line::Marker(text) => {
+							return Spans::from(Span::styled(
+								format!("== {} ==", text),
+								Style::default()
+									.fg(Color::Yellow)
+									.add_modifier(Modifier::BOLD)))
+						},
+					}
+				})
+				.collect();
 
-	board.reboot()?;
-	let ret = board.expect_boot(&mut output);
+			let mut title = format!("Console: {} ({})", name, console.path);
+			if scroll > 0 {
+				title.push_str(&format!(" [{} lines back, End to follow]", scroll));
+			}
+			(title, lines)
+		},
+	};
 
-	if ret.is_err() {
-		error!("Expect boot failed, likely the uart is in use!");
-		board.power_off()?;
-		return ret;
-	}
+	return Paragraph::new(Text::from(lines))
+		.block(Block::default().borders(Borders::ALL).title(title))
+}
 
-	board.expect_shutdown(&mut output)?;
-	return board.power_off()
+fn job_items(jobs: &Jobs) -> Vec<ListItem<'static>>
+{
+	return jobs.snapshot()
+		.iter()
+		.rev()
+		.map(|job| {
+			let (state, colour) = match &job.state {
+				JobState::Running => ("running".to_string(), Color::Yellow),
+				JobState::Cancelling => ("cancelling".to_string(), Color::Gray),
+				JobState::Succeeded => ("done".to_string(), Color::Green),
+				JobState::Failed(e) => (format!("failed: {}", e), Color::Red),
+				JobState::Cancelled => ("cancelled".to_string(), Color::Gray),
+			};
+
+			return ListItem::new(format!("{} {} ({}s): {}", job.board, job.action,
+						     job.duration().as_secs(), state))
+				.style(
+					Style::default().fg(colour)
+				)
+		})
+		.collect()
 }
 
 fn create_centered_rect(percent_x: u16, percent_y: u16, rect: Rect) -> Rect {
@@ -203,7 +276,7 @@ fn action_menu(ui_state: &mut UIState)
 			("Reboot", reboot),
 			("Power off", power_off),
 			("Power on", power_on),
-			("Boot test", boot_test),
+			("Boot test", boottest::run),
 		]);
 
 	let action_items: Vec<ListItem> = ui_state.actions.items.iter()
@@ -227,28 +300,34 @@ fn action_menu(ui_state: &mut UIState)
 	ui_state.show_popup = true;
 }
 
-fn perform_action(ui_state: &mut UIState) -> Result<(), Box<dyn std::error::Error>>
+fn perform_action(ui_state: &mut UIState, jobs: &mut Jobs)
 {
 	let board = ui_state.clone().selected_board();
 
 	if board.is_none() {
-		return Ok(())
+		return;
 	}
 
-	let action = ui_state.clone().selected_action();
-
-	if action.is_none() {
-		toggle_power_state(board.unwrap())?;
-	} else {
-		action.unwrap()(board.unwrap())?;
+	match ui_state.actions.state.selected() {
+		Some(selected) => {
+			let (name, action) = ui_state.actions.items[selected];
+			jobs.spawn(board.unwrap(), name, action);
+		},
+		None => jobs.spawn(board.unwrap(), "Switch power", toggle_power_state),
 	}
 
-	return Ok(());
+	ui_state.show_popup = false;
 }
 
-pub fn run_interactively(input_file: String) -> Result<(), Box<dyn std::error::Error>>
+pub fn run_interactively(board_names: &[String], input_file: String)
+-> Result<(), Box<dyn std::error::Error>>
 {
-	let boards = boards::get_all_boards_from_config(input_file)?;
+	/* everything it would print would end up all over the screen */
+	if power::dry_run() {
+		return Err(Box::new(UiError::new("there is no dry run of the TUI, try mock boards")))
+	}
+
+	let boards = boards::get_boards_from_config(board_names, input_file)?;
 	let mut ui_state = UIState::new();
 	let stdout = io::stdout();
 	let backend = CrosstermBackend::new(stdout);
@@ -259,19 +338,32 @@ pub fn run_interactively(input_file: String) -> Result<(), Box<dyn std::error::E
 	terminal.clear()?;
 
 	for board in boards.iter() {
-		ui_state.boards.items.push(&*board);
+		ui_state.boards.items.push(board);
 	}
 
+	let poller = Poller::start(boards.clone(), POLL_INTERVAL);
+	let mut jobs = Jobs::default();
+	let mut attached: Option<(String, Result<Arc<Console>, String>)> = None;
+
 	loop {
+		let mut oldest: Option<Instant> = None;
 
 		let items: Vec<ListItem> = ui_state
 			.boards.items.iter()
 			.map(|i| {
-				let mut colour = Color::Gray;
-				let status = i.is_powered();
-				if status.is_ok() && status.unwrap() {
-					colour = Color::Blue;
-				}
+				let colour = match poller.get(&i.name) {
+					Some(state) => {
+						if oldest.is_none() || Some(state.updated) < oldest {
+							oldest = Some(state.updated);
+						}
+						match (state.powered, state.error) {
+							(Some(true), _) => Color::Blue,
+							(_, Some(_)) => Color::Red,
+							_ => Color::Gray,
+						}
+					},
+					None => Color::Gray,
+				};
 
 				return ListItem::new(i.name.clone())
 					.style(
@@ -280,8 +372,13 @@ pub fn run_interactively(input_file: String) -> Result<(), Box<dyn std::error::E
 			})
 			.collect();
 
+		let title = match oldest {
+			Some(updated) => format!("List (polled {}s ago)", updated.elapsed().as_secs()),
+			None => "List (polling)".to_string(),
+		};
+
 		let items = List::new(items)
-			.block(Block::default().borders(Borders::ALL).title("List"))
+			.block(Block::default().borders(Borders::ALL).title(title))
 			.highlight_style(
 				Style::default()
 					.bg(Color::White)
@@ -318,6 +415,18 @@ pub fn run_interactively(input_file: String) -> Result<(), Box<dyn std::error::E
 						KeyCode::Down => ui_state.boards.next(),
 						KeyCode::Up => ui_state.boards.previous(),
 						KeyCode::Enter => action_menu(&mut ui_state),
+						KeyCode::Char('r') => poller.refresh(),
+						KeyCode::PageUp => ui_state.console_scroll += CONSOLE_PAGE,
+						KeyCode::PageDown => {
+							ui_state.console_scroll =
+								ui_state.console_scroll.saturating_sub(CONSOLE_PAGE);
+						},
+						KeyCode::End => ui_state.console_scroll = 0,
+						KeyCode::Char('c') => {
+							if let Some(board) = ui_state.clone().selected_board() {
+								jobs.cancel(&board.name);
+							}
+						},
 						_ => {}
 					}
 				}
@@ -335,17 +444,48 @@ pub fn run_interactively(input_file: String) -> Result<(), Box<dyn std::error::E
 					KeyCode::Up => ui_state.actions.previous(),
 					KeyCode::Esc => ui_state.show_popup = false,
 					KeyCode::Enter => {
-						let _err = perform_action(&mut ui_state);
+						perform_action(&mut ui_state, &mut jobs);
+						poller.refresh();
 					},
 					_ => {}
 				}
 			}
 		}
 
+		let selected = ui_state.clone().selected_board();
+		let attached_to = attached.as_ref().map(|(name, _)| return name.clone());
+		if selected.map(|board| return board.name.clone()) != attached_to {
+			ui_state.console_scroll = 0;
+			attached = selected.map(|board| {
+				let console = match board.primary_uart.as_str() {
+					"n/a" => Err("no uart configured".to_string()),
+					uart => console::attach(uart).map_err(|e| return e.to_string()),
+				};
+				return (board.name.clone(), console)
+			});
+		}
+
+		let job_list = List::new(job_items(&jobs))
+			.block(Block::default().borders(Borders::ALL).title("Jobs (c to cancel)"));
+
 		terminal.draw(|frame| {
 			useable_window = entire_window.split(frame.size());
 
-			frame.render_widget(ui_state.text_box.clone(), useable_window[1]);
+			let right_side = Layout::default()
+				.direction(Direction::Vertical)
+				.constraints(
+					[
+						Constraint::Percentage(70),
+						Constraint::Percentage(30),
+					]
+					.as_ref(),
+				)
+				.split(useable_window[1]);
+
+			let console_pane = console_pane(&attached, ui_state.console_scroll,
+							right_side[0].height.saturating_sub(2) as usize);
+			frame.render_widget(console_pane, right_side[0]);
+			frame.render_widget(job_list, right_side[1]);
 			frame.render_stateful_widget(items.clone(), useable_window[0],
 						     &mut ui_state.boards.state);
 			if ui_state.show_popup {
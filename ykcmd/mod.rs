@@ -3,9 +3,12 @@
 #![deny(clippy::implicit_return)]
 #![allow(clippy::needless_return)]
 
-use serde_yaml::Value;
-use std::{fs, process::Command, fmt, thread, time};
-use crate::boards;
+use serde::Serialize;
+use std::{fmt, sync::Mutex, thread, time};
+use crate::boards::{self, Ops};
+use crate::console;
+use crate::poller::{self, PowerState};
+use crate::power::{self, Action, HubNotFoundError, PowerBackend};
 use log::debug;
 
 #[derive(Debug)]
@@ -21,7 +24,7 @@ impl YkmdError {
 
 impl fmt::Display for YkmdError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		return write!(f, "ykushcmd failed: {}", self.details)
+		return write!(f, "yk*cmd failed: {}", self.details)
 	}
 }
 
@@ -31,52 +34,41 @@ impl std::error::Error for YkmdError {
 	}
 }
 
-fn power(board: String, serial: String, port: String, direction: String, command: String)
--> Result<(), Box<dyn std::error::Error>>
+/* far longer than any of them should take, even with a hub being slow */
+const TOOL_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// Run one of the Yepkit tools and hand back its stdout
+fn run(command: &[&str], args: &[&str]) -> Result<String, Box<dyn std::error::Error>>
 {
-	let output = Command::new("sh")
-		.arg("-c")
-		.arg(
-			&format!("{} -s {} -{} {}",
-				command,
-				serial,
-				direction.chars().next().unwrap(),
-				port)
-		)
-		.output()
-		.expect("failed to execute process");
+	let argv: Vec<String> = command.iter().chain(args).map(|arg| return arg.to_string()).collect();
+	let output = power::execute(&argv, TOOL_TIMEOUT).map_err(|e| return YkmdError::new(&e))?;
 
 	if !output.status.success() {
-		return Err(Box::new(YkmdError::new("failed to power direction")));
+		return Err(Box::new(YkmdError::new(&power::failure(&argv, &output))));
 	}
 
-	debug!("{} attached to {}@{} powered {}.", board, serial, port, direction);
+	return Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn power(serial: &str, port: &str, direction: &str, command: &[&str])
+-> Result<(), Box<dyn std::error::Error>>
+{
+	let flag = match direction {
+		"up" => "-u",
+		_ => "-d",
+	};
+
+	run(command, &["-s", serial, flag, port])?;
+
+	debug!("{}@{} powered {}.", serial, port, direction);
 	return Ok(())
 }
 
-fn port_status(serial: String, port: String, command: String)
+fn port_status(serial: &str, port: &str, command: &[&str])
 -> Result<bool, Box<dyn std::error::Error>>
 {
-	let output = Command::new("sh")
-		.arg("-c")
-		.arg(
-			&format!("{} -s {} -g {}",
-				command,
-				serial,
-				port)
-		)
-		.output()
-		.expect("failed to execute process");
-
-	if !output.status.success() {
-		return Err(Box::new(YkmdError::new("failed to get port status")));
-	}
+	let stdout = run(command, &["-s", serial, "-g", port])?;
 
-	let stdout = match String::from_utf8(output.stdout) {
-		Ok(v) => v,
-		Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-	};
-	
 	if stdout.contains("ON") {
 		return Ok(true)
 	} else {
@@ -84,160 +76,520 @@ fn port_status(serial: String, port: String, command: String)
 	}
 }
 
-fn format_command(yk_board_type: String, command: &mut String)
+fn list(command: &[&str]) -> Result<Vec<String>, Box<dyn std::error::Error>>
+{
+	let stdout = run(command, &["-l"])?;
+
+	/* "1. Board found with serial number: YK21614" */
+	let serials = stdout
+		.lines()
+		.filter_map(|line| return line.split_once("serial number:"))
+		.map(|(_, serial)| return serial.trim().to_string())
+		.collect();
+
+	return Ok(serials)
+}
+
+/// the command line an action comes down to, the hub being looked for first
+fn explain(serial: &str, port: &str, action: Action, command: &[&str]) -> String
+{
+	let args = match action {
+		Action::On => vec!["-s", serial, "-u", port],
+		Action::Off => vec!["-s", serial, "-d", port],
+		Action::Status => vec!["-s", serial, "-g", port],
+		Action::List => vec!["-l"],
+	};
+
+	return [command, &args].concat().join(" ")
+}
+
+fn find_hub(serial: &str, command: &[&str]) -> Result<(), Box<dyn std::error::Error>>
+{
+	if !list(command)?.iter().any(|found| return found == serial) {
+		return Err(Box::new(HubNotFoundError::new(serial)))
+	}
+
+	return Ok(())
+}
+
+const YKUSH_COMMAND: &[&str] = &["ykushcmd", "ykush"];
+const YKUR_COMMAND: &[&str] = &["ykurcmd"];
+
+/// A downstream port on a YKUSH usb hub, driven by ykushcmd
+#[derive(Debug)]
+pub struct Ykush {
+	serial_number: String,
+	port_number: String,
+}
+
+impl Ykush {
+	pub fn new(serial_number: &str, port_number: &str) -> Ykush {
+		return Ykush {
+			serial_number: serial_number.to_string(),
+			port_number: port_number.to_string(),
+		}
+	}
+}
+
+impl PowerBackend for Ykush {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUSH_COMMAND)?;
+		return power(&self.serial_number, &self.port_number, "up", YKUSH_COMMAND)
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUSH_COMMAND)?;
+		return power(&self.serial_number, &self.port_number, "down", YKUSH_COMMAND)
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUSH_COMMAND)?;
+		return port_status(&self.serial_number, &self.port_number, YKUSH_COMMAND)
+	}
+
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		return list(YKUSH_COMMAND)
+	}
+
+	fn hub(&self) -> String
+	{
+		return format!("ykush:{}", self.serial_number)
+	}
+
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUSH_COMMAND)?;
+		return ports
+			.iter()
+			.map(|port| return port_status(&self.serial_number, port, YKUSH_COMMAND))
+			.collect()
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		return explain(&self.serial_number, &self.port_number, action, YKUSH_COMMAND)
+	}
+
+	fn off_found(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return power(&self.serial_number, &self.port_number, "down", YKUSH_COMMAND)
+	}
+}
+
+/// The relay on a YKUR board, driven by ykurcmd
+#[derive(Debug)]
+pub struct Ykur {
+	serial_number: String,
+	port_number: String,
+}
+
+impl Ykur {
+	pub fn new(serial_number: &str, port_number: &str) -> Ykur {
+		return Ykur {
+			serial_number: serial_number.to_string(),
+			port_number: port_number.to_string(),
+		}
+	}
+}
+
+impl PowerBackend for Ykur {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUR_COMMAND)?;
+		return power(&self.serial_number, &self.port_number, "up", YKUR_COMMAND)
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUR_COMMAND)?;
+		return power(&self.serial_number, &self.port_number, "down", YKUR_COMMAND)
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUR_COMMAND)?;
+		return port_status(&self.serial_number, &self.port_number, YKUR_COMMAND)
+	}
+
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		return list(YKUR_COMMAND)
+	}
+
+	fn hub(&self) -> String
+	{
+		return format!("ykur:{}", self.serial_number)
+	}
+
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		find_hub(&self.serial_number, YKUR_COMMAND)?;
+		return ports
+			.iter()
+			.map(|port| return port_status(&self.serial_number, port, YKUR_COMMAND))
+			.collect()
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		return explain(&self.serial_number, &self.port_number, action, YKUR_COMMAND)
+	}
+
+	fn off_found(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		return power(&self.serial_number, &self.port_number, "down", YKUR_COMMAND)
+	}
+}
+
+pub fn power_off_boards(board_names: &[String], input_file: String)
 -> Result<(), Box<dyn std::error::Error>>
 {
-	match yk_board_type.as_str() {
-		"usb" => *command = "ykushcmd ykush".to_string(),
-		"relay" => *command = "ykurcmd".to_string(),
-		_ => return Err(Box::new(YkmdError::new("Unsupported yk board type"))),
+	for board in boards::get_boards_from_config(board_names, input_file)? {
+		power_off(&board)?;
 	}
 
 	return Ok(())
 }
 
-pub fn power_off_board(board_name: String, input_file: String)
+pub fn power_off(board: &boards::Board)
 -> Result<(), Box<dyn std::error::Error>>
 {
-	let board = boards::get_board_from_config(board_name, input_file)?;
-	return power_off(board.name, board.yk_serial_number, board.yk_port_number,
-			 board.power_source)
+	debug!("{} attached to {}@{}", board.name, board.yk_serial_number,
+	       board.yk_port_number);
+	board.power.off()?;
+	debug!("{} powered down.", board.name);
+
+	return Ok(())
 }
 
-pub fn power_off(board_name: String, serial_number: String, port_number: String, power_source: String)
+pub fn power_on(board: &boards::Board)
 -> Result<(), Box<dyn std::error::Error>>
 {
-	let mut command: String = String::new();
+	debug!("{} attached to {}@{}", board.name, board.yk_serial_number,
+	       board.yk_port_number);
+	board.power.on()?;
+	debug!("{} powered up.", board.name);
 
-	format_command(power_source, &mut command)?;
+	return Ok(())
+}
 
-	let output = Command::new("sh")
-		.arg("-c")
-		.arg(format!("{} -l ", command))
-		.output()
-		.expect("failed to execute process");
+pub fn power_on_boards(board_names: &[String], input_file: String)
+-> Result<(), Box<dyn std::error::Error>>
+{
+	for board in boards::get_boards_from_config(board_names, input_file)? {
+		power_on(&board)?;
+	}
 
-	let stdout = match String::from_utf8(output.stdout) {
-		Ok(v) => v,
-		Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-	};
+	return Ok(())
+}
 
-	if !stdout.contains(&serial_number) {
-		return Err(Box::new(YkmdError::new(&format!(
-			"board with serial {} not found", serial_number))))
+pub fn reboot_boards(board_names: &[String], input_file: String)
+-> Result<(), Box<dyn std::error::Error>>
+{
+	for board in boards::get_boards_from_config(board_names, input_file)? {
+		reboot(&board)?;
 	}
-	
-	debug!("{} attached to {}@{}", board_name, serial_number, port_number);
-	power(board_name, serial_number, port_number, "down".to_string(), command)?;
 
 	return Ok(())
 }
 
-pub fn power_on(board_name: String, serial_number: String, port_number: String, power_source: String)
+pub fn reboot(board: &boards::Board)
 -> Result<(), Box<dyn std::error::Error>>
 {
-	let mut command: String = String::new();
+	debug!("{} attached to {}@{}", board.name, board.yk_serial_number,
+	       board.yk_port_number);
+	board.power.reboot()?;
+	debug!("{} power cycled.", board.name);
 
-	format_command(power_source, &mut command)?;
+	return Ok(())
+}
 
-	let output = Command::new("sh")
-		.arg("-c")
-		.arg(format!("{} -l ", command))
-		.output()
-		.expect("failed to execute process");
+pub fn is_powered(board: &boards::Board)
+-> Result<bool, Box<dyn std::error::Error>>
+{
+	return board.power.status()
+}
 
-	let stdout = match String::from_utf8(output.stdout) {
-		Ok(v) => v,
-		Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-	};
+#[derive(Serialize)]
+pub struct BoardStatus {
+	pub name: String,
+	pub power_source: String,
+	pub serial: String,
+	pub port: String,
+	pub uart: String,
+	pub uart_present: bool,
+	pub powered: Option<bool>,
+	pub error: Option<String>,
+}
 
-	if !stdout.contains(&serial_number) {
-		return Err(Box::new(YkmdError::new(&format!(
-			"board with serial {} not found", serial_number))))
+impl BoardStatus {
+	pub fn of(board: &boards::Board, power_state: Option<&PowerState>) -> BoardStatus
+	{
+		let (powered, error) = match power_state {
+			Some(state) => (state.powered, state.error.clone()),
+			None => (None, Some("not polled".to_string())),
+		};
+
+		return BoardStatus {
+			name: board.name.clone(),
+			power_source: board.power_source.clone(),
+			serial: board.yk_serial_number.clone(),
+			port: board.yk_port_number.clone(),
+			uart: board.primary_uart.clone(),
+			uart_present: console::present(&board.primary_uart),
+			powered,
+			error,
+		}
 	}
-	
-	debug!("{} attached to {}@{}", board_name, serial_number, port_number);
-	power(board_name, serial_number, port_number, "up".to_string(), command)?;
 
-	return Ok(())
+	fn state(&self) -> &str
+	{
+		match self.powered {
+			Some(true) => return "on",
+			Some(false) => return "off",
+			None => return "error",
+		}
+	}
 }
 
-pub fn power_on_board(board_name: String, input_file: String)
--> Result<(), Box<dyn std::error::Error>>
+pub fn print_table(rows: &[Vec<String>])
 {
-	let board = boards::get_board_from_config(board_name, input_file)?;
-	return power_on(board.name, board.yk_serial_number, board.yk_port_number,
-			board.power_source)
+	let mut widths: Vec<usize> = Vec::new();
+
+	for row in rows.iter() {
+		for (i, cell) in row.iter().enumerate() {
+			if widths.len() <= i {
+				widths.push(0);
+			}
+			widths[i] = widths[i].max(cell.len());
+		}
+	}
+
+	for row in rows.iter() {
+		let line: Vec<String> = row
+			.iter()
+			.enumerate()
+			.map(|(i, cell)| return format!("{:<width$}", cell, width = widths[i]))
+			.collect();
+		println!("{}", line.join("  ").trim_end());
+	}
 }
 
-pub fn reboot_board(board_name: String, input_file: String)
+pub fn status(board_names: &[String], input_file: String, json: bool)
 -> Result<(), Box<dyn std::error::Error>>
 {
-	let board = boards::get_board_from_config(board_name, input_file)?;
+	let boards = boards::get_boards_from_config(board_names, input_file)?;
+	let power_states = poller::poll(&boards);
+	let statuses: Vec<BoardStatus> = boards
+		.iter()
+		.map(|board| return BoardStatus::of(board, power_states.get(&board.name)))
+		.collect();
+
+	if json {
+		println!("{}", serde_json::to_string_pretty(&statuses)?);
+		return Ok(())
+	}
+
+	let mut rows = vec![
+		["BOARD", "TYPE", "SERIAL", "PORT", "POWER", "UART"]
+			.iter()
+			.map(|heading| return heading.to_string())
+			.collect()
+	];
+
+	for status in statuses.iter() {
+		let uart = match (status.uart.as_str(), status.uart_present) {
+			("n/a", _) => "n/a".to_string(),
+			(uart, true) => uart.to_string(),
+			(uart, false) => format!("{} (missing)", uart),
+		};
+
+		rows.push(vec![
+			status.name.clone(),
+			status.power_source.clone(),
+			status.serial.clone(),
+			status.port.clone(),
+			status.state().to_string(),
+			uart,
+		]);
+	}
+
+	print_table(&rows);
 
-	return reboot(board.name, board.yk_serial_number, board.yk_port_number,
-		      board.power_source)
+	for status in statuses.iter() {
+		if let Some(error) = &status.error {
+			println!("{}: {}", status.name, error);
+		}
+	}
+
+	return Ok(())
 }
 
-pub fn reboot(board_name: String, serial_number: String, port_number: String, power_source: String)
+/// Print the boards from the config along with whether the switch they hang
+/// off is actually attached, listing each kind of switch only the once.
+pub fn list_boards(board_names: &[String], input_file: String)
 -> Result<(), Box<dyn std::error::Error>>
 {
-
-	power_off(board_name.clone(), serial_number.clone(), port_number.clone(),
-		  power_source.clone())?;
-	thread::sleep(time::Duration::from_millis(1000));
-	power_on(board_name, serial_number, port_number,
-		 power_source)?;
+	let mut attached: Vec<(String, Vec<String>)> = Vec::new();
+
+	for board in boards::get_boards_from_config(board_names, input_file)? {
+		if !attached.iter().any(|(source, _)| return *source == board.power_source) {
+			attached.push((board.power_source.clone(), Vec::new()));
+		}
+
+		let (_, serials) = attached
+			.iter_mut()
+			.find(|(source, _)| return *source == board.power_source)
+			.unwrap();
+		/* some switches can only vouch for themselves, not the others */
+		let mut looked = Ok(());
+		if !serials.contains(&board.yk_serial_number) {
+			looked = board.power.list().map(|found| return serials.extend(found));
+		}
+
+		let present = match (looked, serials.contains(&board.yk_serial_number)) {
+			(_, true) => "attached".to_string(),
+			(Ok(()), false) => "missing".to_string(),
+			(Err(e), false) => format!("unknown, {}", e),
+		};
+
+		println!("{:<12} {:<6} {:<10} {:<4} {}", board.name, board.power_source,
+			 board.yk_serial_number, board.yk_port_number, present);
+	}
 
 	return Ok(())
 }
 
-pub fn is_powered(board: &boards::Board)
--> Result<bool, Box<dyn std::error::Error>>
+/// ask a board to shut itself down over its console, giving it up to its
+/// shutdown timeout to get there
+fn shut_down(board: &boards::Board) -> Result<(), Box<dyn std::error::Error>>
 {
-	let mut command: String = String::new();
+	let console = console::attach(&board.primary_uart)?;
+	let mut session = console.session(board.shutdown_timeout);
 
-	format_command((*board).power_source.to_string(), &mut command)?;
+	return board.expect_shutdown(&mut session, &mut Vec::new())
+}
 
-	let output = Command::new("sh")
-		.arg("-c")
-		.arg(format!("{} -l ", command))
-		.output()
-		.expect("failed to execute process");
+/// how putting a board to bed went
+enum Night {
+	/// with whether it shut itself down first, or why not
+	Off(Result<(), String>),
+	AlreadyOff,
+	Failed(String),
+}
 
-	let stdout = match String::from_utf8(output.stdout) {
-		Ok(v) => v,
-		Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+/// Shut a board down over its console if it is on & has a uart, then cut
+/// the power regardless, once nobody else is cutting power on the hub.
+fn tuck_in(board: &boards::Board, state: Option<&PowerState>, cutting: &Mutex<()>) -> Night
+{
+	let powered = state.and_then(|state| return state.powered);
+
+	/* can't tell if it's on, so no sense waiting on its console */
+	let shut_down = match (powered, board.primary_uart.as_str()) {
+		(Some(false), _) => return Night::AlreadyOff,
+		(None, _) => Err("couldn't tell if it was on".to_string()),
+		(Some(true), "n/a") => Err("no uart to shut it down over".to_string()),
+		(Some(true), _) => shut_down(board).map_err(|e| return e.to_string()),
 	};
 
-	if !stdout.contains(&board.yk_serial_number) {
-		return Err(Box::new(YkmdError::new(&format!(
-			"board with serial {} not found", board.yk_serial_number))))
-	}
+	/* polling the hub found it already, no sense in every board looking again */
+	let found = state.is_some_and(|state| return state.error.is_none());
 
-	return port_status(board.yk_serial_number.clone(), board.yk_port_number.clone(),
-			   command);
+	let _cutting = cutting.lock().unwrap();
+	let off = match found {
+		true => board.power.off_found(),
+		false => board.power_off(),
+	};
+	match off {
+		Ok(()) => return Night::Off(shut_down),
+		Err(e) => return Night::Failed(e.to_string()),
+	}
 }
 
-pub fn goodnight(input_file: String) -> Result<(), Box<dyn std::error::Error>>
+/// Power boards off for the night, letting the ones that are on and have a
+/// uart shut down cleanly first so their filesystems survive. Power gets cut
+/// whether or not they manage it. Each hub is seen to at once, its boards
+/// shutting down side by side before their power goes one after the other.
+pub fn goodnight(board_names: &[String], input_file: String)
+-> Result<(), Box<dyn std::error::Error>>
 {
-	let contents = fs::read_to_string(input_file.clone())?;
+	let boards = boards::get_boards_from_config(board_names, input_file)?;
+	let power_states = poller::poll(&boards);
+	let hubs = poller::by_hub(&boards);
+
+	let mut nights: Vec<(String, Night)> = thread::scope(|scope| {
+		let hubs: Vec<thread::ScopedJoinHandle<Vec<(String, Night)>>> = hubs
+			.iter()
+			.map(|(hub, members)| {
+				let power_states = &power_states;
+
+				return scope.spawn(move || {
+					debug!("putting {} board(s) on {} to bed", members.len(), hub);
+					let cutting = Mutex::new(());
+
+					return thread::scope(|scope| {
+						let members: Vec<thread::ScopedJoinHandle<(String, Night)>> = members
+							.iter()
+							.map(|board| {
+								let cutting = &cutting;
+
+								return scope.spawn(move || {
+									let state = power_states.get(&board.name);
+									let night = tuck_in(board, state, cutting);
+									return (board.name.clone(), night)
+								})
+							})
+							.collect();
+
+						return members
+							.into_iter()
+							.map(|member| return member.join().unwrap())
+							.collect()
+					})
+				})
+			})
+			.collect();
+
+		return hubs
+			.into_iter()
+			.flat_map(|hub| return hub.join().unwrap())
+			.collect()
+	});
+
+	/* back into the order they were asked for in */
+	nights.sort_by_key(|(name, _)| return boards.iter().position(|board| return board.name == *name));
+
+	let mut rows = vec![
+		["BOARD", "RESULT", "DETAIL"]
+			.iter()
+			.map(|heading| return heading.to_string())
+			.collect(),
+	];
+	let mut failed = 0;
+
+	for (name, night) in nights.iter() {
+		let (result, detail) = match night {
+			Night::Off(Ok(())) => ("off", "shut down cleanly".to_string()),
+			Night::Off(Err(e)) => ("off", format!("power cut, {}", e)),
+			Night::AlreadyOff => ("already off", String::new()),
+			Night::Failed(e) => {
+				failed += 1;
+				("failed", e.clone())
+			},
+		};
+		rows.push(vec![name.clone(), result.to_string(), detail]);
+	}
 
-	let config: Value = serde_yaml::from_str(&contents)?;
+	print_table(&rows);
 
-	let board_configs = config
-		.get("boards")
-		.ok_or_else(|| return boards::ConfigParsingError::new("No boards found"))?
-		.as_mapping();
-	
-	if board_configs.is_none() {
-		return Err(Box::new(YkmdError::new("No boards found")))
-	}
-	for board in board_configs.unwrap().iter() {
-		let board_name = board.0.as_str().unwrap();
-		debug!("Trying to power down {}", board_name);
-		let _ = power_off_board(String::from(board_name), input_file.clone());
+	if failed > 0 {
+		return Err(Box::new(YkmdError::new(&format!(
+			"{} of {} board(s) could not be powered off", failed, nights.len()))))
 	}
-	
+
 	return Ok(())
 }
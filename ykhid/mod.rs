@@ -0,0 +1,616 @@
+// SPDX-License-Identifier: LGPL-3.0-only
+
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::{fmt, fs, io::{Read, Write}, os::unix::io::AsRawFd, path::PathBuf, sync::Arc};
+use crate::power::{Action, HubNotFoundError, PowerBackend};
+use log::debug;
+
+/*
+ * Talks to Yepkit boards directly over /dev/hidrawN rather than going via
+ * ykushcmd & ykurcmd. Every command is a single 64 byte output report, with
+ * the board answering with a 64 byte input report:
+ *   0x0n: port n down, 0x1n: port n up, 0x2n: get port n (0xa is all ports)
+ * Newer firmware prefixes the answer with 0x01, older firmware does not.
+ */
+pub const REPORT_SIZE: usize = 64;
+const YEPKIT_VENDOR_ID: u16 = 0x04d8;
+const TIMEOUT_MS: i32 = 1000;
+
+#[derive(Debug)]
+pub struct HidError {
+	details: String
+}
+
+impl HidError {
+	pub fn new(msg: &str) -> HidError {
+		return HidError{details: msg.to_string()}
+	}
+}
+
+impl fmt::Display for HidError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		return write!(f, "hidraw failed: {}", self.details)
+	}
+}
+
+impl std::error::Error for HidError {
+	fn description(&self) -> &str {
+		return &self.details
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+	Ykush,
+	Ykush3,
+	YkushXs,
+	Ykur,
+}
+
+impl Model {
+	fn from_ids(vendor_id: u16, product_id: u16) -> Option<Model>
+	{
+		if vendor_id != YEPKIT_VENDOR_ID {
+			return None
+		}
+
+		match product_id {
+			0x0042 | 0xf2f7 => return Some(Model::Ykush),
+			0xf11b => return Some(Model::Ykush3),
+			0xf0cd => return Some(Model::YkushXs),
+			0xf1cb => return Some(Model::Ykur),
+			_ => return None,
+		}
+	}
+
+	/// whether this model is driven by boards of config type power_source
+	fn serves(&self, power_source: &str) -> bool
+	{
+		match self {
+			Model::Ykush | Model::Ykush3 | Model::YkushXs => return power_source == "usb",
+			Model::Ykur => return power_source == "relay",
+		}
+	}
+
+	/// map a port from the config to the index used in reports
+	fn port_index(&self, port: &str) -> Result<u8, Box<dyn std::error::Error>>
+	{
+		let index = match (self, port) {
+			(Model::Ykush | Model::Ykush3, "1") => 0x1,
+			(Model::Ykush | Model::Ykush3, "2") => 0x2,
+			(Model::Ykush | Model::Ykush3, "3") => 0x3,
+			(Model::Ykush | Model::Ykush3, "a") => 0xa,
+			(Model::YkushXs, "1") => 0x1,
+			(Model::Ykur, "r") => 0x1,
+			_ => return Err(Box::new(HidError::new(&format!(
+				"port {} does not exist on a {:?}", port, self)))),
+		};
+
+		return Ok(index)
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct HidDevice {
+	pub path: PathBuf,
+	pub model: Model,
+	pub serial: String,
+}
+
+/// How reports get to and from the boards, split out so that something
+/// other than a real /dev/hidrawN can stand in for them.
+pub trait Transport: fmt::Debug + Send + Sync {
+	/// every Yepkit board currently attached
+	fn devices(&self) -> Result<Vec<HidDevice>, Box<dyn std::error::Error>>;
+	fn exchange(&self, device: &HidDevice, report: &[u8; REPORT_SIZE])
+	-> Result<[u8; REPORT_SIZE], Box<dyn std::error::Error>>;
+}
+
+#[derive(Debug)]
+pub struct Hidraw {
+	sysfs_root: PathBuf,
+	dev_root: PathBuf,
+}
+
+impl Default for Hidraw {
+	fn default() -> Hidraw
+	{
+		return Hidraw {
+			sysfs_root: PathBuf::from("/sys/class/hidraw"),
+			dev_root: PathBuf::from("/dev"),
+		}
+	}
+}
+
+/// Pull the model and serial out of a hidraw device's uevent, which looks like
+///   HID_ID=0003:000004D8:0000F2F7
+///   HID_UNIQ=YK21614
+fn parse_uevent(uevent: &str, path: PathBuf) -> Option<HidDevice>
+{
+	let mut model = None;
+	let mut serial = None;
+
+	for line in uevent.lines() {
+		if let Some(id) = line.strip_prefix("HID_ID=") {
+			let fields: Vec<&str> = id.split(':').collect();
+			if fields.len() != 3 {
+				return None
+			}
+			let vendor_id = u32::from_str_radix(fields[1], 16).ok()?;
+			let product_id = u32::from_str_radix(fields[2], 16).ok()?;
+			model = Model::from_ids(vendor_id as u16, product_id as u16);
+		} else if let Some(uniq) = line.strip_prefix("HID_UNIQ=") {
+			serial = Some(uniq.trim().to_string());
+		}
+	}
+
+	return Some(HidDevice {
+		path,
+		model: model?,
+		serial: serial?,
+	})
+}
+
+fn wait_readable(file: &fs::File) -> Result<(), Box<dyn std::error::Error>>
+{
+	let mut fds = libc::pollfd {
+		fd: file.as_raw_fd(),
+		events: libc::POLLIN,
+		revents: 0,
+	};
+
+	let ret = unsafe { libc::poll(&mut fds, 1, TIMEOUT_MS) };
+	if ret < 0 {
+		return Err(Box::new(std::io::Error::last_os_error()))
+	}
+	if ret == 0 {
+		return Err(Box::new(HidError::new("timed out waiting for a report")))
+	}
+
+	return Ok(())
+}
+
+impl Transport for Hidraw {
+	fn devices(&self) -> Result<Vec<HidDevice>, Box<dyn std::error::Error>>
+	{
+		let mut devices = Vec::new();
+
+		/* no hidraw devices at all means no class directory either */
+		let entries = match fs::read_dir(&self.sysfs_root) {
+			Ok(entries) => entries,
+			Err(_) => return Ok(devices),
+		};
+
+		for entry in entries {
+			let entry = entry?;
+			let uevent = match fs::read_to_string(entry.path().join("device/uevent")) {
+				Ok(uevent) => uevent,
+				Err(_) => continue,
+			};
+
+			if let Some(device) = parse_uevent(&uevent, self.dev_root.join(entry.file_name())) {
+				devices.push(device);
+			}
+		}
+
+		return Ok(devices)
+	}
+
+	fn exchange(&self, device: &HidDevice, report: &[u8; REPORT_SIZE])
+	-> Result<[u8; REPORT_SIZE], Box<dyn std::error::Error>>
+	{
+		let mut file = fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open(&device.path)?;
+
+		/* unnumbered reports still need a leading report id of 0 */
+		let mut output = [0u8; REPORT_SIZE + 1];
+		output[1..].copy_from_slice(report);
+		file.write_all(&output)?;
+
+		wait_readable(&file)?;
+		let mut input = [0u8; REPORT_SIZE];
+		let read = file.read(&mut input)?;
+		if read == 0 {
+			return Err(Box::new(HidError::new("empty report")))
+		}
+
+		return Ok(input)
+	}
+}
+
+pub fn encode(command: u8, port_index: u8) -> [u8; REPORT_SIZE]
+{
+	let mut report = [0u8; REPORT_SIZE];
+	report[0] = command | port_index;
+	return report
+}
+
+/// Work out whether a port is on from the answer to a get report
+pub fn decode_status(response: &[u8; REPORT_SIZE], port_index: u8)
+-> Result<bool, Box<dyn std::error::Error>>
+{
+	let state = if response[0] == 0x01 && (response[1] & 0x0f) == port_index {
+		response[1]
+	} else {
+		response[0]
+	};
+
+	match state >> 4 {
+		0x0 => return Ok(false),
+		0x1 => return Ok(true),
+		_ => return Err(Box::new(HidError::new(&format!(
+			"unexpected port status {:#04x}", state)))),
+	}
+}
+
+const PORT_DOWN: u8 = 0x00;
+const PORT_UP: u8 = 0x10;
+const PORT_GET: u8 = 0x20;
+
+/// A port on any Yepkit board, found by serial number amongst the attached
+/// hidraw devices every time it is used so that replugging is harmless.
+#[derive(Debug)]
+pub struct Yepkit {
+	power_source: String,
+	serial_number: String,
+	port_number: String,
+	transport: Arc<dyn Transport>,
+}
+
+impl Yepkit {
+	pub fn new(power_source: &str, serial_number: &str, port_number: &str,
+		   transport: Arc<dyn Transport>) -> Yepkit
+	{
+		return Yepkit {
+			power_source: power_source.to_string(),
+			serial_number: serial_number.to_string(),
+			port_number: port_number.to_string(),
+			transport,
+		}
+	}
+
+	fn find_device(&self) -> Result<HidDevice, Box<dyn std::error::Error>>
+	{
+		let mut devices: Vec<HidDevice> = self.transport.devices()?
+			.into_iter()
+			.filter(|device| {
+				return device.model.serves(&self.power_source)
+					&& device.serial == self.serial_number
+			})
+			.collect();
+
+		/* switching whichever came first could well be the wrong board */
+		if devices.len() > 1 {
+			let paths: Vec<String> = devices
+				.iter()
+				.map(|device| return device.path.display().to_string())
+				.collect();
+			return Err(Box::new(HidError::new(&format!("{} boards have serial {}: {}",
+				devices.len(), self.serial_number, paths.join(", ")))))
+		}
+
+		return devices.pop().ok_or_else(|| return HubNotFoundError::new(&self.serial_number).into())
+	}
+
+	fn send(&self, command: u8) -> Result<([u8; REPORT_SIZE], u8), Box<dyn std::error::Error>>
+	{
+		let device = self.find_device()?;
+		let port_index = device.model.port_index(&self.port_number)?;
+
+		debug!("sending {:#04x} to {} via {}", command | port_index,
+		       device.serial, device.path.display());
+
+		let response = self.transport.exchange(&device, &encode(command, port_index))?;
+		return Ok((response, port_index))
+	}
+}
+
+impl PowerBackend for Yepkit {
+	fn on(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		self.send(PORT_UP)?;
+		return Ok(())
+	}
+
+	fn off(&self) -> Result<(), Box<dyn std::error::Error>>
+	{
+		self.send(PORT_DOWN)?;
+		return Ok(())
+	}
+
+	fn status(&self) -> Result<bool, Box<dyn std::error::Error>>
+	{
+		let (response, port_index) = self.send(PORT_GET)?;
+		return decode_status(&response, port_index)
+	}
+
+	fn list(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>
+	{
+		let serials = self.transport.devices()?
+			.into_iter()
+			.filter(|device| return device.model.serves(&self.power_source))
+			.map(|device| return device.serial)
+			.collect();
+
+		return Ok(serials)
+	}
+
+	fn hub(&self) -> String
+	{
+		return format!("hidraw:{}:{}", self.power_source, self.serial_number)
+	}
+
+	fn port_states(&self, ports: &[String]) -> Result<Vec<bool>, Box<dyn std::error::Error>>
+	{
+		let device = self.find_device()?;
+
+		return ports
+			.iter()
+			.map(|port| {
+				let port_index = device.model.port_index(port)?;
+				let response = self.transport.exchange(&device, &encode(PORT_GET, port_index))?;
+				return decode_status(&response, port_index)
+			})
+			.collect()
+	}
+
+	fn explain(&self, action: Action) -> String
+	{
+		let command = match action {
+			Action::On => PORT_UP,
+			Action::Off => PORT_DOWN,
+			Action::Status => PORT_GET,
+			Action::List => return format!("scan /dev/hidraw* for {} boards", self.power_source),
+		};
+
+		return format!("send {:#04x} (port {}) to {} {} over hidraw", command, self.port_number,
+			       self.power_source, self.serial_number)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Mutex;
+
+	/// Boards that answer get reports with whatever state their ports are
+	/// in, noting down every report they are sent
+	#[derive(Debug, Default)]
+	struct Fake {
+		devices: Vec<HidDevice>,
+		/// sent back, as is, in place of a real answer
+		reply: Option<[u8; REPORT_SIZE]>,
+		/// newer firmware puts 0x01 in front of the answer
+		prefixed: bool,
+		sent: Mutex<Vec<(PathBuf, u8)>>,
+		ports: Mutex<Vec<u8>>,
+	}
+
+	impl Transport for Fake {
+		fn devices(&self) -> Result<Vec<HidDevice>, Box<dyn std::error::Error>>
+		{
+			return Ok(self.devices.clone())
+		}
+
+		fn exchange(&self, device: &HidDevice, report: &[u8; REPORT_SIZE])
+		-> Result<[u8; REPORT_SIZE], Box<dyn std::error::Error>>
+		{
+			assert!(report[1..].iter().all(|byte| return *byte == 0));
+			self.sent.lock().unwrap().push((device.path.clone(), report[0]));
+
+			if let Some(reply) = self.reply {
+				return Ok(reply)
+			}
+
+			let (command, port) = (report[0] & 0xf0, report[0] & 0x0f);
+			let mut ports = self.ports.lock().unwrap();
+			match command {
+				PORT_UP => ports.push(port),
+				PORT_DOWN => ports.retain(|on| return *on != port),
+				_ => (),
+			}
+			let state = match ports.contains(&port) {
+				true => 0x10 | port,
+				false => port,
+			};
+
+			let mut answer = [0u8; REPORT_SIZE];
+			match self.prefixed {
+				true => answer[..2].copy_from_slice(&[0x01, state]),
+				false => answer[0] = state,
+			}
+			return Ok(answer)
+		}
+	}
+
+	fn device(path: &str, model: Model, serial: &str) -> HidDevice
+	{
+		return HidDevice {
+			path: PathBuf::from(path),
+			model,
+			serial: serial.to_string(),
+		}
+	}
+
+	fn attached(devices: Vec<HidDevice>) -> Arc<Fake>
+	{
+		return Arc::new(Fake{devices, ..Fake::default()})
+	}
+
+	fn sent(fake: &Fake) -> Vec<(PathBuf, u8)>
+	{
+		return fake.sent.lock().unwrap().clone()
+	}
+
+	fn report(byte: u8) -> [u8; REPORT_SIZE]
+	{
+		let mut report = [0u8; REPORT_SIZE];
+		report[0] = byte;
+		return report
+	}
+
+	#[test]
+	fn reports_carry_command_and_port()
+	{
+		let cases = [
+			(Model::Ykush, "1", PORT_UP, 0x11),
+			(Model::Ykush, "3", PORT_DOWN, 0x03),
+			(Model::Ykush, "a", PORT_GET, 0x2a),
+			(Model::Ykush3, "2", PORT_UP, 0x12),
+			(Model::Ykush3, "3", PORT_GET, 0x23),
+			(Model::YkushXs, "1", PORT_DOWN, 0x01),
+			(Model::YkushXs, "1", PORT_GET, 0x21),
+			(Model::Ykur, "r", PORT_UP, 0x11),
+			(Model::Ykur, "r", PORT_DOWN, 0x01),
+		];
+
+		for (model, port, command, expected) in cases {
+			let index = model.port_index(port).unwrap();
+			assert_eq!(encode(command, index), report(expected), "{:?} port {}", model, port);
+		}
+	}
+
+	#[test]
+	fn ports_a_model_does_not_have_are_refused()
+	{
+		for (model, port) in [(Model::Ykush, "4"), (Model::Ykush3, "r"), (Model::YkushXs, "2"),
+				      (Model::Ykur, "1")] {
+			assert!(model.port_index(port).is_err(), "{:?} port {}", model, port);
+		}
+	}
+
+	#[test]
+	fn status_comes_from_either_firmware()
+	{
+		/* old firmware answers with just the state */
+		assert!(decode_status(&report(0x11), 0x1).unwrap());
+		assert!(!decode_status(&report(0x02), 0x2).unwrap());
+
+		/* new firmware says 0x01 first */
+		let mut answer = report(0x01);
+		answer[1] = 0x13;
+		assert!(decode_status(&answer, 0x3).unwrap());
+		answer[1] = 0x03;
+		assert!(!decode_status(&answer, 0x3).unwrap());
+
+		/* old firmware's port 1 being off is not to be taken for the prefix */
+		assert!(!decode_status(&report(0x01), 0x1).unwrap());
+	}
+
+	#[test]
+	fn malformed_status_is_an_error()
+	{
+		for answer in [0xff, 0x21, 0x51] {
+			assert!(decode_status(&report(answer), 0x1).is_err(), "{:#04x}", answer);
+		}
+
+		let mut answer = report(0x01);
+		answer[1] = 0xe2;
+		assert!(decode_status(&answer, 0x2).is_err());
+	}
+
+	#[test]
+	fn boards_are_found_by_serial()
+	{
+		let fake = attached(vec![
+			device("/dev/hidraw0", Model::Ykush, "YK111"),
+			device("/dev/hidraw1", Model::Ykush3, "YK222"),
+			device("/dev/hidraw2", Model::Ykur, "YK333"),
+		]);
+
+		Yepkit::new("usb", "YK222", "2", fake.clone()).on().unwrap();
+		Yepkit::new("relay", "YK333", "r", fake.clone()).off().unwrap();
+
+		assert_eq!(sent(&fake), vec![(PathBuf::from("/dev/hidraw1"), 0x12),
+					     (PathBuf::from("/dev/hidraw2"), 0x01)]);
+	}
+
+	#[test]
+	fn missing_boards_are_not_found()
+	{
+		let fake = attached(vec![device("/dev/hidraw0", Model::Ykush, "YK111")]);
+
+		let error = Yepkit::new("usb", "YK999", "1", fake.clone()).on().unwrap_err();
+		assert!(error.is::<HubNotFoundError>());
+
+		/* the right serial on the wrong kind of board is no good either */
+		let error = Yepkit::new("relay", "YK111", "r", fake.clone()).on().unwrap_err();
+		assert!(error.is::<HubNotFoundError>());
+
+		assert!(sent(&fake).is_empty());
+	}
+
+	#[test]
+	fn duplicate_serials_are_refused()
+	{
+		let fake = attached(vec![
+			device("/dev/hidraw0", Model::Ykush, "YK111"),
+			device("/dev/hidraw1", Model::Ykush3, "YK111"),
+		]);
+
+		let error = Yepkit::new("usb", "YK111", "1", fake.clone()).on().unwrap_err();
+		assert!(error.is::<HidError>());
+		assert!(error.to_string().contains("/dev/hidraw0, /dev/hidraw1"));
+		assert!(sent(&fake).is_empty());
+
+		/* a relay sharing a hub's serial is still told apart by its kind */
+		let fake = attached(vec![
+			device("/dev/hidraw0", Model::Ykush, "YK111"),
+			device("/dev/hidraw1", Model::Ykur, "YK111"),
+		]);
+		Yepkit::new("relay", "YK111", "r", fake.clone()).on().unwrap();
+		assert_eq!(sent(&fake), vec![(PathBuf::from("/dev/hidraw1"), 0x11)]);
+	}
+
+	#[test]
+	fn switched_ports_read_back()
+	{
+		for prefixed in [false, true] {
+			let fake = Arc::new(Fake {
+				devices: vec![device("/dev/hidraw0", Model::Ykush, "YK111")],
+				prefixed,
+				..Fake::default()
+			});
+			let port = |port: &str| return Yepkit::new("usb", "YK111", port, fake.clone());
+
+			port("1").on().unwrap();
+			port("3").on().unwrap();
+			port("3").off().unwrap();
+			assert!(port("1").status().unwrap());
+
+			let ports = ["1".to_string(), "2".to_string(), "3".to_string()];
+			assert_eq!(port("1").port_states(&ports).unwrap(), vec![true, false, false]);
+		}
+	}
+
+	#[test]
+	fn garbled_answers_fail_status()
+	{
+		let fake = Arc::new(Fake {
+			devices: vec![device("/dev/hidraw0", Model::YkushXs, "YK111")],
+			reply: Some(report(0x7f)),
+			..Fake::default()
+		});
+
+		assert!(Yepkit::new("usb", "YK111", "1", fake).status().is_err());
+	}
+
+	#[test]
+	fn uevents_give_model_and_serial()
+	{
+		let found = parse_uevent("DRIVER=hid-generic\nHID_ID=0003:000004D8:0000F2F7\nHID_UNIQ=YK21614\n",
+					 PathBuf::from("/dev/hidraw3")).unwrap();
+		assert_eq!(found.model, Model::Ykush);
+		assert_eq!(found.serial, "YK21614");
+		assert_eq!(found.path, PathBuf::from("/dev/hidraw3"));
+
+		/* someone else's device, or no serial to go by */
+		assert!(parse_uevent("HID_ID=0003:0000046D:0000C52B\nHID_UNIQ=X\n", PathBuf::new()).is_none());
+		assert!(parse_uevent("HID_ID=0003:000004D8:0000F1CB\n", PathBuf::new()).is_none());
+		assert!(parse_uevent("HID_ID=nonsense\nHID_UNIQ=X\n", PathBuf::new()).is_none());
+	}
+}